@@ -1,11 +1,19 @@
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use tauri::Wry;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast::error::RecvError, mpsc, oneshot};
+
+use secp256k1::schnorrsig::PublicKey;
+use serde_json::json;
 
 use crate::core::{
-    config::Contact,
-    conversations::{Conversation, ConvsNotifications},
+    config::{Contact, EncryptionScheme, Group, RelayConfig},
+    conversations::{Conversation, ConvsNotifications, GroupConversation},
     core::{CoreTaskHandle, CoreTaskHandleEvent},
+    event_cache::EventCacheMetrics,
+    nip11::RelayInformation,
+    nip13::PowCanceller,
+    profiles::{Profile, ProfileUpdate, ProfilesNotifications},
+    relay_pool::RelayRejection,
 };
 
 use thiserror::Error;
@@ -27,6 +35,49 @@ pub enum BrokerEvent {
         url: String,
         resp: Responder<Result<(), String>>,
     },
+    UpdateRelay {
+        relay: RelayConfig,
+        resp: Responder<Result<(), String>>,
+    },
+    ListRelays {
+        resp: Responder<Vec<RelayConfig>>,
+    },
+    GetRelayInfo {
+        url: String,
+        resp: Responder<Result<RelayInformation, String>>,
+    },
+    GetEventCacheMetrics {
+        resp: Responder<Option<EventCacheMetrics>>,
+    },
+    GetProxy {
+        resp: Responder<Option<String>>,
+    },
+    DeleteMessage {
+        message_id: String,
+        resp: Responder<Result<(), String>>,
+    },
+    EditMessage {
+        message_id: String,
+        content: String,
+        resp: Responder<Result<Vec<RelayRejection>, String>>,
+    },
+    React {
+        pk: String,
+        message_id: String,
+        reaction: String,
+        resp: Responder<Result<(), String>>,
+    },
+    GetPowDifficulty {
+        resp: Responder<Option<u8>>,
+    },
+    SetPowDifficulty {
+        difficulty: Option<u8>,
+        resp: Responder<Result<(), String>>,
+    },
+    SetProxy {
+        proxy: Option<String>,
+        resp: Responder<Result<(), String>>,
+    },
     ConnectRelay {
         url: String,
     },
@@ -37,10 +88,27 @@ pub enum BrokerEvent {
         new_contact: Contact,
         resp: Responder<()>,
     },
+    AddContactByNip05 {
+        alias: String,
+        identifier: String,
+        resp: Responder<Result<Contact, String>>,
+    },
     RemoveContact {
         contact: Contact,
         resp: Responder<()>,
     },
+    SetContactEncryption {
+        pk: String,
+        encryption: EncryptionScheme,
+        resp: Responder<Result<(), String>>,
+    },
+    GetContactListPublishing {
+        resp: Responder<bool>,
+    },
+    SetContactListPublishing {
+        publish: bool,
+        resp: Responder<Result<(), String>>,
+    },
     SubscribeInRelays {
         pk: String,
     },
@@ -61,10 +129,55 @@ pub enum BrokerEvent {
     SendMessage {
         pk: String,
         content: String,
+        reply_to: Option<String>,
+        resp: Responder<Result<Vec<RelayRejection>, String>>,
+    },
+    CreateGroup {
+        participants: Vec<PublicKey>,
+        subject: Option<String>,
+        resp: Responder<Result<Group, String>>,
+    },
+    RenameGroup {
+        group_id: String,
+        subject: String,
+        resp: Responder<Result<(), String>>,
+    },
+    AddGroupParticipant {
+        group_id: String,
+        pk: PublicKey,
+        resp: Responder<Result<Group, String>>,
+    },
+    RemoveGroupParticipant {
+        group_id: String,
+        pk: PublicKey,
+        resp: Responder<Result<Group, String>>,
+    },
+    GetGroupConversation {
+        group_id: String,
+        resp: Responder<Result<GroupConversation, String>>,
+    },
+    ListGroups {
+        resp: Responder<Vec<Group>>,
+    },
+    SendGroupMessage {
+        group_id: String,
+        content: String,
+        resp: Responder<Result<Vec<RelayRejection>, String>>,
     },
     LoadConfigs {
         resp: Responder<(Vec<String>, Vec<Contact>)>,
     },
+    GetProfile {
+        pk: String,
+        resp: Responder<Option<Profile>>,
+    },
+    GetUserProfile {
+        resp: Responder<Option<Profile>>,
+    },
+    SetUserProfile {
+        update: ProfileUpdate,
+        resp: Responder<Result<Option<Profile>, String>>,
+    },
 }
 
 pub type Responder<T> = oneshot::Sender<T>;
@@ -78,12 +191,74 @@ async fn handle_broker_event(
     core_handle: &mut CoreTaskHandle,
 ) -> Result<(), BrokerEventError> {
     match broker_event {
-        BrokerEvent::SendMessage { pk, content } => {
-            core_handle
-                .send_msg_to_contact(&pk, &content)
+        BrokerEvent::SendMessage {
+            pk,
+            content,
+            reply_to,
+            resp,
+        } => {
+            let res = core_handle
+                .send_msg_to_contact(&pk, &content, reply_to.as_deref())
                 .await
-                .map_err(|err| BrokerEventError::CommandFailed(err.to_string()))?;
-            Ok(())
+                .map_err(|e| e.to_string());
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)
+        }
+        BrokerEvent::CreateGroup {
+            participants,
+            subject,
+            resp,
+        } => resp
+            .send(
+                core_handle
+                    .create_group(participants, subject)
+                    .map_err(|e| e.to_string()),
+            )
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::RenameGroup {
+            group_id,
+            subject,
+            resp,
+        } => {
+            let res = core_handle
+                .rename_group(&group_id, subject)
+                .await
+                .map_err(|e| e.to_string());
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)
+        }
+        BrokerEvent::AddGroupParticipant { group_id, pk, resp } => resp
+            .send(
+                core_handle
+                    .add_group_participant(&group_id, pk)
+                    .map_err(|e| e.to_string()),
+            )
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::RemoveGroupParticipant { group_id, pk, resp } => resp
+            .send(
+                core_handle
+                    .remove_group_participant(&group_id, pk)
+                    .map_err(|e| e.to_string()),
+            )
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::GetGroupConversation { group_id, resp } => resp
+            .send(
+                core_handle
+                    .get_group_conv(group_id)
+                    .ok_or_else(|| "Group not found".to_string()),
+            )
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::ListGroups { resp } => resp
+            .send(core_handle.list_groups())
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::SendGroupMessage {
+            group_id,
+            content,
+            resp,
+        } => {
+            let res = core_handle
+                .send_msg_to_group(&group_id, &content)
+                .await
+                .map_err(|e| e.to_string());
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)
         }
         BrokerEvent::SetConversation { pk } => {
             if let Some(_conv) = core_handle.get_conv(pk) {
@@ -121,22 +296,101 @@ async fn handle_broker_event(
         }
 
         BrokerEvent::AddRelay { url, resp } => {
-            if let CoreTaskHandleEvent::RelayAdded(Ok(_)) = core_handle.add_relay(url) {
-                resp.send(Ok(())).map_err(|_e| BrokerEventError::FailedSend)
+            if let CoreTaskHandleEvent::RelayAdded(Ok(_)) = core_handle.add_relay(url).await {
+                resp.send(Ok(()))
+                    .map_err(|_e| BrokerEventError::FailedSend)?;
                 //   update_config_state(&event_sink, &core_handle).await;
+                Ok(core_handle.publish_relay_lists().await)
             } else {
                 resp.send(Err(format!("Could not add relay")))
                     .map_err(|_e| BrokerEventError::FailedSend)
             }
         }
         BrokerEvent::RemoveRelay { url, resp } => {
-            if let CoreTaskHandleEvent::RemovedRelay(Ok(_)) = core_handle.remove_relay(url) {
-                resp.send(Ok(())).map_err(|_e| BrokerEventError::FailedSend)
+            if let CoreTaskHandleEvent::RemovedRelay(Ok(_)) = core_handle.remove_relay(url).await {
+                resp.send(Ok(()))
+                    .map_err(|_e| BrokerEventError::FailedSend)?;
+                Ok(core_handle.publish_relay_lists().await)
             } else {
                 resp.send(Err(format!("Failed to remove")))
                     .map_err(|_e| BrokerEventError::FailedSend)
             }
         }
+        BrokerEvent::UpdateRelay { relay, resp } => match core_handle.update_relay(relay).await {
+            Ok(()) => {
+                resp.send(Ok(()))
+                    .map_err(|_e| BrokerEventError::FailedSend)?;
+                Ok(core_handle.publish_relay_lists().await)
+            }
+            Err(e) => resp
+                .send(Err(e.to_string()))
+                .map_err(|_e| BrokerEventError::FailedSend),
+        },
+        BrokerEvent::ListRelays { resp } => resp
+            .send(core_handle.list_relays())
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::GetRelayInfo { url, resp } => resp
+            .send(
+                core_handle
+                    .get_relay_info(url)
+                    .await
+                    .map_err(|e| e.to_string()),
+            )
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::GetEventCacheMetrics { resp } => resp
+            .send(core_handle.event_cache_metrics().await)
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::DeleteMessage { message_id, resp } => {
+            let res = core_handle
+                .delete_message(&message_id)
+                .await
+                .map_err(|e| e.to_string());
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)
+        }
+        BrokerEvent::EditMessage {
+            message_id,
+            content,
+            resp,
+        } => {
+            let res = core_handle
+                .edit_message(&message_id, &content)
+                .await
+                .map_err(|e| e.to_string());
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)
+        }
+        BrokerEvent::React {
+            pk,
+            message_id,
+            reaction,
+            resp,
+        } => {
+            let res = core_handle
+                .react(&pk, &message_id, &reaction)
+                .await
+                .map_err(|e| e.to_string());
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)
+        }
+        BrokerEvent::GetPowDifficulty { resp } => resp
+            .send(core_handle.get_pow_difficulty())
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::SetPowDifficulty { difficulty, resp } => resp
+            .send(
+                core_handle
+                    .set_pow_difficulty(difficulty)
+                    .map_err(|e| e.to_string()),
+            )
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::GetProxy { resp } => resp
+            .send(core_handle.get_proxy())
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::SetProxy { proxy, resp } => resp
+            .send(
+                core_handle
+                    .set_proxy(proxy)
+                    .await
+                    .map_err(|e| e.to_string()),
+            )
+            .map_err(|_e| BrokerEventError::FailedSend),
         BrokerEvent::ConnectRelay { url } => Ok(core_handle.connect_relay(url).await),
         BrokerEvent::DisconnectRelay { url } => Ok(core_handle.disconnect_relay(url).await),
         BrokerEvent::SubscribeInRelays { pk: _ } => Ok(core_handle.subscribe().await),
@@ -145,13 +399,64 @@ async fn handle_broker_event(
             resp.send(()).map_err(|_e| BrokerEventError::FailedSend);
 
             //Update filters and resubscribe based on updated conversations
+            core_handle.subscribe().await;
+            Ok(core_handle.publish_contact_list().await)
+        }
+        BrokerEvent::AddContactByNip05 {
+            alias,
+            identifier,
+            resp,
+        } => {
+            let res = core_handle
+                .add_contact_by_nip05(&alias, &identifier)
+                .await
+                .map_err(|e| e.to_string());
+            let added = res.is_ok();
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)?;
 
-            Ok(core_handle.subscribe().await)
+            if added {
+                core_handle.subscribe().await;
+            }
+            Ok(())
         }
         BrokerEvent::RemoveContact { contact, resp } => {
             let _res = core_handle.remove_contact(contact).await;
             resp.send(()).map_err(|_e| BrokerEventError::FailedSend)
         }
+        BrokerEvent::SetContactEncryption {
+            pk,
+            encryption,
+            resp,
+        } => resp
+            .send(
+                core_handle
+                    .set_contact_encryption(&pk, encryption)
+                    .map_err(|e| e.to_string()),
+            )
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::GetContactListPublishing { resp } => resp
+            .send(core_handle.get_contact_list_publishing())
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::SetContactListPublishing { publish, resp } => {
+            let res = core_handle
+                .set_contact_list_publishing(publish)
+                .await
+                .map_err(|e| e.to_string());
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)
+        }
+        BrokerEvent::GetProfile { pk, resp } => resp
+            .send(core_handle.get_profile(&pk))
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::GetUserProfile { resp } => resp
+            .send(core_handle.get_user_profile())
+            .map_err(|_e| BrokerEventError::FailedSend),
+        BrokerEvent::SetUserProfile { update, resp } => {
+            let res = core_handle
+                .set_user_profile(update)
+                .await
+                .map_err(|e| e.to_string());
+            resp.send(res).map_err(|_e| BrokerEventError::FailedSend)
+        }
         BrokerEvent::LoadConfigs { resp } => resp
             .send(core_handle.get_config())
             .map_err(|_e| BrokerEventError::FailedSend),
@@ -161,12 +466,27 @@ async fn handle_broker_event(
 pub async fn start_broker(
     mut broker_receiver: mpsc::Receiver<BrokerEvent>,
     main_window: tauri::Window<Wry>,
+    pow_canceller: PowCanceller,
 ) {
-    let mut core_handle = CoreTaskHandle::new();
+    let mut core_handle = CoreTaskHandle::new(pow_canceller);
 
     let mut rec_convs_noti = core_handle.get_convs_notifications();
+    let mut rec_profiles_noti = core_handle.get_profiles_notifications();
     //  let ev_sink_clone = event_sink.clone();
 
+    let profiles_window = main_window.clone();
+    tokio::spawn(async move {
+        while let Ok(noti) = rec_profiles_noti.recv().await {
+            match noti {
+                ProfilesNotifications::ProfileUpdated(profile) => {
+                    profiles_window
+                        .emit("profile_updated", profile)
+                        .expect("Can't communicate back to the main window");
+                }
+            }
+        }
+    });
+
     tokio::spawn(async move {
         while let Ok(noti) = rec_convs_noti.recv().await {
             match noti {
@@ -176,16 +496,139 @@ pub async fn start_broker(
                         .emit("new_message", new_msg)
                         .expect("Can't communicate back to the main window");
                 }
+                ConvsNotifications::NewGroupMessage { group_id, message } => {
+                    debug!("{:?}", message);
+                    main_window
+                        .emit(
+                            "new_group_message",
+                            json!({ "group_id": group_id, "message": message }),
+                        )
+                        .expect("Can't communicate back to the main window");
+                }
+                ConvsNotifications::GroupUpdated(group) => {
+                    main_window
+                        .emit("group_updated", group)
+                        .expect("Can't communicate back to the main window");
+                }
+                ConvsNotifications::ContactUpdated(contact) => {
+                    main_window
+                        .emit("contact_updated", contact)
+                        .expect("Can't communicate back to the main window");
+                }
+                ConvsNotifications::ContactRemoved(contact) => {
+                    main_window
+                        .emit("contact_removed", contact)
+                        .expect("Can't communicate back to the main window");
+                }
+                ConvsNotifications::ReactionAdded {
+                    conversation_id,
+                    message_id,
+                    pubkey,
+                    reaction,
+                } => {
+                    main_window
+                        .emit(
+                            "reaction_added",
+                            json!({
+                                "conversation_id": conversation_id,
+                                "message_id": message_id,
+                                "pubkey": pubkey,
+                                "reaction": reaction,
+                            }),
+                        )
+                        .expect("Can't communicate back to the main window");
+                }
+                ConvsNotifications::QuoteResolved {
+                    conversation_id,
+                    message_id,
+                    quoted,
+                } => {
+                    main_window
+                        .emit(
+                            "quote_resolved",
+                            json!({
+                                "conversation_id": conversation_id,
+                                "message_id": message_id,
+                                "quoted": quoted,
+                            }),
+                        )
+                        .expect("Can't communicate back to the main window");
+                }
+                ConvsNotifications::MessageEdited {
+                    conversation_id,
+                    message,
+                } => {
+                    main_window
+                        .emit(
+                            "message_edited",
+                            json!({ "conversation_id": conversation_id, "message": message }),
+                        )
+                        .expect("Can't communicate back to the main window");
+                }
+                ConvsNotifications::MessageDeleted {
+                    conversation_id,
+                    message_id,
+                } => {
+                    main_window
+                        .emit(
+                            "message_deleted",
+                            json!({ "conversation_id": conversation_id, "message_id": message_id }),
+                        )
+                        .expect("Can't communicate back to the main window");
+                }
             }
         }
     });
 
-    core_handle.connect_all_relays().await;
+    //Some relay events, like contact lists, need the core handle
+    let mut rec_relay_noti = core_handle.get_noti_ch();
+    //Relays connect in the background and are subscribed once up, commands
+    //are handled meanwhile
+    let mut rec_connections = core_handle
+        .take_connection_ch()
+        .expect("Relay connections channel already taken");
+    let mut rec_relay_infos = core_handle
+        .take_relay_info_ch()
+        .expect("Relay information channel already taken");
+    let mut rec_disconnections = core_handle
+        .take_disconnection_ch()
+        .expect("Relay disconnections channel already taken");
+    let mut rec_mined = core_handle
+        .take_mined_ch()
+        .expect("Mined events channel already taken");
+
     core_handle.subscribe().await;
+    core_handle.connect_all_relays();
     info!("Broker initialized and waiting for commands");
-    while let Some(broker_event) = broker_receiver.recv().await {
-        if let Err(e) = handle_broker_event(broker_event, &mut core_handle).await {
-            error!("broker_event error: {:?}", e.to_string())
+    loop {
+        tokio::select! {
+            broker_event = broker_receiver.recv() => match broker_event {
+                Some(broker_event) => {
+                    if let Err(e) = handle_broker_event(broker_event, &mut core_handle).await {
+                        error!("broker_event error: {:?}", e.to_string())
+                    }
+                }
+                None => break,
+            },
+            notification = rec_relay_noti.recv() => match notification {
+                Ok(notification) => core_handle.handle_relay_notification(notification).await,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Missed {} relay notifications", missed)
+                }
+                Err(RecvError::Closed) => break,
+            },
+            Some(url) = rec_disconnections.recv() => {
+                core_handle.handle_relay_disconnection(&url)
+            }
+            Some(connection) = rec_connections.recv() => {
+                core_handle.handle_relay_connection(connection).await
+            }
+            Some(url) = rec_relay_infos.recv() => {
+                core_handle.apply_relay_info(&url).await
+            }
+            Some((url, ev)) = rec_mined.recv() => {
+                core_handle.send_mined(&url, ev).await
+            }
         }
     }
 }
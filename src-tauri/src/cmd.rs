@@ -2,7 +2,14 @@ use std::str::FromStr;
 
 use crate::{
     broker::BrokerEvent,
-    core::{config::Contact, conversations::Conversation},
+    core::{
+        config::{Contact, EncryptionScheme, Group, RelayConfig},
+        conversations::{Conversation, GroupConversation},
+        event_cache::EventCacheMetrics,
+        nip11::RelayInformation,
+        profiles::{Profile, ProfileUpdate},
+        relay_pool::RelayRejection,
+    },
     AppState,
 };
 use log::debug;
@@ -41,6 +48,56 @@ pub async fn get_conversation(
     res_rx.await.map_err(|err| format!("{}", err))?
 }
 
+#[command]
+pub async fn get_profile(
+    pk: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<Profile>, String> {
+    debug!("get_profile command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::GetProfile { pk, resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))
+}
+
+#[command]
+pub async fn get_user_profile(
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<Profile>, String> {
+    debug!("get_user_profile command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::GetUserProfile { resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))
+}
+
+#[command]
+pub async fn set_user_profile(
+    profile: ProfileUpdate,
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<Profile>, String> {
+    debug!("set_user_profile command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::SetUserProfile {
+            update: profile,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
 #[command]
 pub async fn restore_key_pair(
     sk: String,
@@ -95,6 +152,61 @@ pub async fn add_contact(
     Err(format!("Invalid PK {}", pk))
 }
 
+#[command]
+pub async fn add_contact_by_nip05(
+    alias: String,
+    identifier: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Contact, String> {
+    debug!("add_contact_by_nip05 command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::AddContactByNip05 {
+            alias,
+            identifier,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn get_contact_list_publishing(
+    state: tauri::State<'_, AppState>,
+) -> Result<bool, String> {
+    debug!("get_contact_list_publishing command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::GetContactListPublishing { resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))
+}
+
+#[command]
+pub async fn set_contact_list_publishing(
+    publish: bool,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    debug!("set_contact_list_publishing command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::SetContactListPublishing {
+            publish,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
 #[command]
 pub async fn remove_contact(
     contact: Contact,
@@ -115,6 +227,27 @@ pub async fn remove_contact(
     res_rx.await.map_err(|e| format!("{}", e))
 }
 
+#[command]
+pub async fn set_contact_encryption(
+    pk: String,
+    encryption: EncryptionScheme,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    debug!("set_contact_encryption command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::SetContactEncryption {
+            pk,
+            encryption,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
 #[command]
 pub async fn add_relay(url: String, state: tauri::State<'_, AppState>) -> Result<(), String> {
     debug!("add_relay command called");
@@ -128,6 +261,205 @@ pub async fn add_relay(url: String, state: tauri::State<'_, AppState>) -> Result
     res_rx.await.map_err(|err| format!("{}", err)).unwrap()
 }
 
+#[command]
+pub async fn update_relay(
+    relay: RelayConfig,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    debug!("update_relay command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::UpdateRelay {
+            relay,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn list_relays(state: tauri::State<'_, AppState>) -> Result<Vec<RelayConfig>, String> {
+    debug!("list_relays command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::ListRelays { resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))
+}
+
+#[command]
+pub async fn get_relay_info(
+    url: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<RelayInformation, String> {
+    debug!("get_relay_info command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::GetRelayInfo { url, resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn get_event_cache_metrics(
+    state: tauri::State<'_, AppState>,
+) -> Result<Option<EventCacheMetrics>, String> {
+    debug!("get_event_cache_metrics command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::GetEventCacheMetrics { resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))
+}
+
+#[command]
+pub async fn delete_message(
+    message_id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    debug!("delete_message command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::DeleteMessage {
+            message_id,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn edit_message(
+    message_id: String,
+    content: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<RelayRejection>, String> {
+    debug!("edit_message command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::EditMessage {
+            message_id,
+            content,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+//`pk` is the contact, or the group id for group messages
+#[command]
+pub async fn react(
+    pk: String,
+    message_id: String,
+    reaction: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    debug!("react command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::React {
+            pk,
+            message_id,
+            reaction,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn get_pow_difficulty(state: tauri::State<'_, AppState>) -> Result<Option<u8>, String> {
+    debug!("get_pow_difficulty command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::GetPowDifficulty { resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))
+}
+
+#[command]
+pub async fn set_pow_difficulty(
+    difficulty: Option<u8>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    debug!("set_pow_difficulty command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::SetPowDifficulty {
+            difficulty,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+//Doesn't go through the broker, which is busy awaiting the mining.
+//Returns false if nothing was being mined
+#[command]
+pub async fn cancel_pow(state: tauri::State<'_, AppState>) -> Result<bool, String> {
+    debug!("cancel_pow command called");
+    Ok(state.pow_canceller.cancel())
+}
+
+#[command]
+pub async fn get_proxy(state: tauri::State<'_, AppState>) -> Result<Option<String>, String> {
+    debug!("get_proxy command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::GetProxy { resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))
+}
+
+#[command]
+pub async fn set_proxy(
+    proxy: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    debug!("set_proxy command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::SetProxy {
+            proxy,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
 #[command]
 pub async fn remove_relay(url: String, state: tauri::State<'_, AppState>) -> Result<(), String> {
     debug!("remove_relay command called");
@@ -144,18 +476,166 @@ pub async fn remove_relay(url: String, state: tauri::State<'_, AppState>) -> Res
 pub async fn send_msg(
     pk: String,
     content: String,
+    reply_to: Option<String>,
     state: tauri::State<'_, AppState>,
-) -> Result<(), String> {
+) -> Result<Vec<RelayRejection>, String> {
     debug!("send_msg command called");
-    let (_, res_rx) = oneshot::channel();
+    let (res_tx, res_rx) = oneshot::channel();
 
     state
         .core_command_sender
-        .send(BrokerEvent::SendMessage { pk, content })
+        .send(BrokerEvent::SendMessage {
+            pk,
+            content,
+            reply_to,
+            resp: res_tx,
+        })
         .await
         .map_err(|e| format!("Send Error: {}", e.to_string()))?;
 
-    res_rx.await.map_err(|e| format!("{}", e))?;
+    res_rx.await.map_err(|e| format!("{}", e))?
+}
+
+#[command]
+pub async fn create_group(
+    participants: Vec<String>,
+    subject: Option<String>,
+    state: tauri::State<'_, AppState>,
+) -> Result<Group, String> {
+    debug!("create_group command called");
+    let participants = participants
+        .iter()
+        .map(|pk| PublicKey::from_str(pk).map_err(|_e| format!("Invalid PK {}", pk)))
+        .collect::<Result<Vec<PublicKey>, String>>()?;
 
-    Ok(())
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::CreateGroup {
+            participants,
+            subject,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn rename_group(
+    group_id: String,
+    subject: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    debug!("rename_group command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::RenameGroup {
+            group_id,
+            subject,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn add_group_participant(
+    group_id: String,
+    pk: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Group, String> {
+    debug!("add_group_participant command called");
+    let pk = PublicKey::from_str(&pk).map_err(|_e| format!("Invalid PK {}", pk))?;
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::AddGroupParticipant {
+            group_id,
+            pk,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn remove_group_participant(
+    group_id: String,
+    pk: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Group, String> {
+    debug!("remove_group_participant command called");
+    let pk = PublicKey::from_str(&pk).map_err(|_e| format!("Invalid PK {}", pk))?;
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::RemoveGroupParticipant {
+            group_id,
+            pk,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn get_group_conversation(
+    group_id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<GroupConversation, String> {
+    debug!("get_group_conversation command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::GetGroupConversation {
+            group_id,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
+}
+
+#[command]
+pub async fn list_groups(state: tauri::State<'_, AppState>) -> Result<Vec<Group>, String> {
+    debug!("list_groups command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::ListGroups { resp: res_tx })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))
+}
+
+#[command]
+pub async fn send_group_msg(
+    group_id: String,
+    content: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<Vec<RelayRejection>, String> {
+    debug!("send_group_msg command called");
+    let (res_tx, res_rx) = oneshot::channel();
+    state
+        .core_command_sender
+        .send(BrokerEvent::SendGroupMessage {
+            group_id,
+            content,
+            resp: res_tx,
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e.to_string()))?;
+
+    res_rx.await.map_err(|err| format!("{}", err))?
 }
@@ -1,11 +1,14 @@
 use log::{info, warn};
 use secp256k1::schnorrsig::PublicKey;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{event_cache::DEFAULT_EVENT_CACHE_SIZE, relay_pool::normalize_relay_url};
 
 use std::{
     collections::HashMap,
     fs::{self, File},
-    io::{BufReader, Error},
+    io::{BufReader, Error, ErrorKind},
     path::PathBuf,
 };
 
@@ -15,14 +18,27 @@ const CONFIG_FILENAME: &str = "config.json";
 #[derive(Clone)]
 pub struct ConfigProvider {
     contacts: HashMap<String, Contact>,
-    relays_url: HashMap<String, String>,
+    relays: HashMap<String, RelayConfig>,
+    groups: HashMap<String, Group>,
+    //Timestamps of our last relay lists, published or restored from relays
+    relay_list_at: i64,
+    dm_relay_list_at: i64,
+    event_cache_size: Option<usize>,
+    proxy: Option<String>,
+    pow_difficulty: Option<u8>,
 }
 
 impl ConfigProvider {
     fn new() -> Self {
         Self {
             contacts: HashMap::new(),
-            relays_url: HashMap::new(),
+            relays: HashMap::new(),
+            groups: HashMap::new(),
+            relay_list_at: 0,
+            dm_relay_list_at: 0,
+            event_cache_size: None,
+            proxy: None,
+            pow_difficulty: None,
         }
     }
 
@@ -35,29 +51,230 @@ impl ConfigProvider {
         self.contacts.remove(&contact.pk.to_string());
         self.save()
     }
+    pub fn set_contact_encryption(
+        &mut self,
+        pk: &str,
+        encryption: EncryptionScheme,
+    ) -> Result<Contact, Error> {
+        let contact = self
+            .contacts
+            .get_mut(pk)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Contact {} not found", pk)))?;
+        contact.encryption = encryption;
+        let contact = contact.clone();
+        self.save()?;
+        Ok(contact)
+    }
+    pub fn set_contact_nip05(
+        &mut self,
+        pk: &str,
+        nip05: Option<Nip05Verification>,
+    ) -> Result<Contact, Error> {
+        let contact = self
+            .contacts
+            .get_mut(pk)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Contact {} not found", pk)))?;
+        contact.nip05 = nip05;
+        let contact = contact.clone();
+        self.save()?;
+        Ok(contact)
+    }
 
-    pub fn add_relay(&mut self, new_relay_url: String) -> Result<(), Error> {
-        self.relays_url
-            .insert(new_relay_url.clone(), new_relay_url.clone());
+    pub fn add_group(&mut self, group: Group) -> Result<(), Error> {
+        self.groups.insert(group.id.clone(), group);
+        self.save()
+    }
+    pub fn remove_group(&mut self, group_id: &str) -> Result<(), Error> {
+        self.groups.remove(group_id);
         self.save()
     }
+
+    //Relay changes are rolled back if they can't be saved
+    pub fn add_relay(&mut self, new_relay_url: String) -> Result<(), Error> {
+        if self.relays.contains_key(&new_relay_url) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Relay {} already exists", new_relay_url),
+            ));
+        }
+        self.relays
+            .insert(new_relay_url.clone(), RelayConfig::new(&new_relay_url));
+        self.save().map_err(|e| {
+            self.relays.remove(&new_relay_url);
+            e
+        })
+    }
     pub fn remove_relay(&mut self, relay_url: &str) -> Result<(), Error> {
-        self.relays_url.remove(relay_url);
+        let relay = self.relays.remove(relay_url).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Relay {} not found", relay_url),
+            )
+        })?;
+        self.save().map_err(|e| {
+            self.relays.insert(relay_url.into(), relay);
+            e
+        })
+    }
+    pub fn update_relay(&mut self, relay: RelayConfig) -> Result<(), Error> {
+        if !self.relays.contains_key(&relay.url) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Relay {} not found", relay.url),
+            ));
+        }
+        self.relays.insert(relay.url.clone(), relay);
+        self.save()
+    }
+    pub fn get_relay(&self, relay_url: &str) -> Option<RelayConfig> {
+        self.relays.get(relay_url).cloned()
+    }
+
+    //Applies our kind 10002 relay list if it is newer than the last one.
+    //Returns the relays that were not configured yet, and the relays
+    //removed because they were left without a role
+    pub fn restore_relay_list(
+        &mut self,
+        read: &[String],
+        write: &[String],
+        created_at: i64,
+    ) -> Result<(Vec<String>, Vec<String>), Error> {
+        if created_at <= self.relay_list_at {
+            return Ok((vec![], vec![]));
+        }
+        let mut new_relays = vec![];
+        for relay in self.relays.values_mut() {
+            relay.read = read.contains(&relay.url);
+            relay.write = write.contains(&relay.url);
+        }
+        for url in read.iter().chain(write.iter()) {
+            let relay = self.relays.entry(url.clone()).or_insert_with(|| {
+                new_relays.push(url.clone());
+                RelayConfig {
+                    dm: false,
+                    ..RelayConfig::new(url)
+                }
+            });
+            relay.read = read.contains(url);
+            relay.write = write.contains(url);
+        }
+        let removed_relays = self.remove_roleless_relays();
+        self.relay_list_at = created_at;
+        self.save()?;
+        Ok((new_relays, removed_relays))
+    }
+
+    //Same as `restore_relay_list` for our kind 10050 DM relay list
+    pub fn restore_dm_relay_list(
+        &mut self,
+        dm: &[String],
+        created_at: i64,
+    ) -> Result<(Vec<String>, Vec<String>), Error> {
+        if created_at <= self.dm_relay_list_at {
+            return Ok((vec![], vec![]));
+        }
+        let mut new_relays = vec![];
+        for url in dm {
+            if !self.relays.contains_key(url) {
+                new_relays.push(url.clone());
+                self.relays.insert(
+                    url.clone(),
+                    RelayConfig {
+                        read: false,
+                        write: false,
+                        ..RelayConfig::new(url)
+                    },
+                );
+            }
+        }
+        for relay in self.relays.values_mut() {
+            relay.dm = dm.contains(&relay.url);
+        }
+        let removed_relays = self.remove_roleless_relays();
+        self.dm_relay_list_at = created_at;
+        self.save()?;
+        Ok((new_relays, removed_relays))
+    }
+
+    fn remove_roleless_relays(&mut self) -> Vec<String> {
+        let removed: Vec<String> = self
+            .relays
+            .values()
+            .filter(|r| !r.read && !r.write && !r.dm)
+            .map(|r| r.url.clone())
+            .collect();
+        for url in &removed {
+            self.relays.remove(url);
+        }
+        removed
+    }
+
+    pub fn set_relay_lists_published_at(&mut self, created_at: i64) -> Result<(), Error> {
+        self.relay_list_at = created_at;
+        self.dm_relay_list_at = created_at;
         self.save()
     }
 
+    pub fn get_contact(&self, pk: &str) -> Option<Contact> {
+        self.contacts.get(pk).cloned()
+    }
+
     pub fn list_contacts(&self) -> Vec<Contact> {
         self.contacts.iter().map(|(_k, v)| v.to_owned()).collect()
     }
 
+    pub fn get_group(&self, group_id: &str) -> Option<Group> {
+        self.groups.get(group_id).cloned()
+    }
+
+    pub fn list_groups(&self) -> Vec<Group> {
+        self.groups.values().cloned().collect()
+    }
+
     pub fn list_relays_url(&self) -> Vec<String> {
-        self.relays_url.iter().map(|(_k, v)| v.to_owned()).collect()
+        self.relays.keys().cloned().collect()
+    }
+
+    pub fn get_event_cache_size(&self) -> usize {
+        self.event_cache_size.unwrap_or(DEFAULT_EVENT_CACHE_SIZE)
+    }
+
+    pub fn list_relays(&self) -> Vec<RelayConfig> {
+        self.relays.values().cloned().collect()
+    }
+
+    pub fn get_proxy(&self) -> Option<String> {
+        self.proxy.clone()
+    }
+    pub fn set_proxy(&mut self, proxy: Option<String>) -> Result<(), Error> {
+        let previous = std::mem::replace(&mut self.proxy, proxy);
+        self.save().map_err(|e| {
+            self.proxy = previous;
+            e
+        })
+    }
+
+    pub fn get_pow_difficulty(&self) -> Option<u8> {
+        self.pow_difficulty
+    }
+    pub fn set_pow_difficulty(&mut self, difficulty: Option<u8>) -> Result<(), Error> {
+        let previous = std::mem::replace(&mut self.pow_difficulty, difficulty);
+        self.save().map_err(|e| {
+            self.pow_difficulty = previous;
+            e
+        })
     }
 
     pub fn save(&self) -> Result<(), Error> {
         let contacts: Vec<Contact> = self.list_contacts();
-        let relays_url: Vec<String> = self.list_relays_url();
-        let config_file = Config::new(contacts, relays_url);
+        let relays: Vec<RelayConfig> = self.list_relays();
+        let groups: Vec<Group> = self.list_groups();
+        let mut config_file = Config::new(contacts, relays, groups);
+        config_file.relay_list_at = self.relay_list_at;
+        config_file.dm_relay_list_at = self.dm_relay_list_at;
+        config_file.event_cache_size = self.event_cache_size;
+        config_file.proxy = self.proxy.clone();
+        config_file.pow_difficulty = self.pow_difficulty;
         let serialized = serde_json::to_string_pretty(&config_file)?;
         let _config_path = Self::get_path();
 
@@ -75,21 +292,41 @@ impl ConfigProvider {
             Ok(file) => {
                 let reader = BufReader::new(file);
                 let config: Config =
-                    serde_json::from_reader(reader).unwrap_or(Config::new(vec![], vec![]));
+                    serde_json::from_reader(reader).unwrap_or(Config::new(vec![], vec![], vec![]));
                 let mut contacts = HashMap::new();
-                let mut relays_url = HashMap::new();
+                let mut relays = HashMap::new();
+                let mut groups = HashMap::new();
 
                 config.contacts.into_iter().for_each(|v| {
                     contacts.insert(v.pk.to_string(), v);
                 });
 
-                config.relays_url.into_iter().for_each(|v| {
-                    relays_url.insert(v.clone(), v);
+                //Configs written before relay markers only have the URLs
+                let legacy_relays = config.relays_url.iter().map(|v| RelayConfig::new(v));
+                //URLs saved before validation are normalized, invalid ones dropped
+                legacy_relays
+                    .chain(config.relays.into_iter())
+                    .for_each(|mut v| match normalize_relay_url(&v.url) {
+                        Ok(url) => {
+                            v.url = url;
+                            relays.insert(v.url.clone(), v);
+                        }
+                        Err(e) => warn!("Dropping relay from config: {}", e),
+                    });
+
+                config.groups.into_iter().for_each(|v| {
+                    groups.insert(v.id.clone(), v);
                 });
 
                 Self {
                     contacts,
-                    relays_url,
+                    relays,
+                    groups,
+                    relay_list_at: config.relay_list_at,
+                    dm_relay_list_at: config.dm_relay_list_at,
+                    event_cache_size: config.event_cache_size,
+                    proxy: config.proxy,
+                    pow_difficulty: config.pow_difficulty,
                 }
             }
             Err(_) => Self::new(),
@@ -101,7 +338,7 @@ impl ConfigProvider {
         path.push(CONFIG_FILENAME);
         path
     }
-    fn get_path() -> PathBuf {
+    pub fn get_path() -> PathBuf {
         let dir = match home::home_dir() {
             Some(path) => {
                 let mut nostr_dir_path = PathBuf::new();
@@ -123,14 +360,78 @@ impl ConfigProvider {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub relays_url: Vec<String>,
+    #[serde(default)]
+    pub relays: Vec<RelayConfig>,
     pub contacts: Vec<Contact>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    #[serde(default)]
+    pub relay_list_at: i64,
+    #[serde(default)]
+    pub dm_relay_list_at: i64,
+    //Received event ids kept in memory to drop duplicates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_cache_size: Option<usize>,
+    //SOCKS5 proxy, e.g. a local Tor daemon, relays connect through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    //NIP-13 difficulty of the events we sign
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pow_difficulty: Option<u8>,
 }
 
 impl Config {
-    pub fn new(contacts: Vec<Contact>, relays_url: Vec<String>) -> Self {
+    pub fn new(contacts: Vec<Contact>, relays: Vec<RelayConfig>, groups: Vec<Group>) -> Self {
         Self {
             contacts,
-            relays_url,
+            relays_url: relays.iter().map(|r| r.url.clone()).collect(),
+            relays,
+            groups,
+            relay_list_at: 0,
+            dm_relay_list_at: 0,
+            event_cache_size: None,
+            proxy: None,
+            pow_difficulty: None,
+        }
+    }
+}
+
+//Relay roles, also published as markers in our relay lists. A relay with
+//only `dm` set is used just to send and receive DMs
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RelayConfig {
+    pub url: String,
+    //Subscribed to
+    pub read: bool,
+    //Events are published there
+    pub write: bool,
+    //Listed in our NIP-17 DM relay list
+    pub dm: bool,
+    //Overrides the app's SOCKS5 proxy for this relay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    //Overrides the app's NIP-13 difficulty for this relay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pow_difficulty: Option<u8>,
+    //Answers the relay's NIP-42 AUTH challenges with our key
+    #[serde(default = "default_auth")]
+    pub auth: bool,
+}
+
+fn default_auth() -> bool {
+    true
+}
+
+impl RelayConfig {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.into(),
+            read: true,
+            write: true,
+            dm: true,
+            proxy: None,
+            pow_difficulty: None,
+            auth: true,
         }
     }
 }
@@ -139,6 +440,15 @@ impl Config {
 pub struct Contact {
     pub alias: String,
     pub pk: PublicKey,
+    //Scheme used to encrypt messages sent to this contact,
+    //received messages are decrypted whatever scheme they use
+    #[serde(default)]
+    pub encryption: EncryptionScheme,
+    #[serde(default)]
+    pub nip05: Option<Nip05Verification>,
+    //Relay where the contact can be found, from NIP-02 contact lists
+    #[serde(default)]
+    pub relay_hint: Option<String>,
 }
 
 impl Contact {
@@ -146,6 +456,80 @@ impl Contact {
         Self {
             alias: alias.into(),
             pk,
+            encryption: EncryptionScheme::default(),
+            nip05: None,
+            relay_hint: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum EncryptionScheme {
+    Nip04,
+    Nip44,
+    //Gift wrapped private DMs, hides sender, recipient and timing
+    Nip17,
+}
+
+impl Default for EncryptionScheme {
+    fn default() -> Self {
+        EncryptionScheme::Nip04
+    }
+}
+
+//Last check of the contact's NIP-05 identifier
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Nip05Verification {
+    pub identifier: String,
+    pub status: Nip05Status,
+    pub checked_at: i64,
+}
+
+impl Nip05Verification {
+    pub fn new(identifier: &str, status: Nip05Status) -> Self {
+        Self {
+            identifier: identifier.into(),
+            status,
+            checked_at: chrono::offset::Utc::now().timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Nip05Status {
+    Verified,
+    //The identifier no longer points to the contact's PK
+    Mismatch,
+}
+
+//NIP-17 group chat. A room is defined by its set of participants, so
+//adding or removing someone yields a different group id
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Group {
+    pub id: String,
+    pub subject: Option<String>,
+    //Other participants, the user is never included
+    pub participants: Vec<PublicKey>,
+}
+
+impl Group {
+    pub fn new(mut participants: Vec<PublicKey>, subject: Option<String>) -> Self {
+        participants.sort_by_key(|pk| pk.to_string());
+        participants.dedup();
+        Self {
+            id: Self::gen_id(&participants),
+            subject,
+            participants,
         }
     }
+
+    pub fn gen_id(participants: &[PublicKey]) -> String {
+        let mut pks: Vec<String> = participants.iter().map(|pk| pk.to_string()).collect();
+        pks.sort();
+        pks.dedup();
+        Sha256::digest(pks.join(",").as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
 }
@@ -1,28 +1,89 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+};
 
-use super::{config::Contact, user::User};
+use super::{
+    config::{Contact, Group},
+    event::{get_kind, get_tag_values, UnsignedEvent},
+    nip09::KIND_DELETION,
+    nip10::{get_edit_of, get_reply_to},
+    nip17::{get_rumor_participants, unwrap_gift, KIND_GIFT_WRAP, KIND_PRIVATE_DM},
+    nip25::{parse_reaction, KIND_REACTION},
+    nip44,
+    user::User,
+};
 use chrono::Duration;
 use log::error;
 use nostr::{util::nip04::decrypt, Event};
-use secp256k1::schnorrsig::PublicKey;
+use secp256k1::{schnorrsig::PublicKey, SecretKey};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::broadcast;
 
+//Messages reacted to before they are received, reactions to more are dropped
+const MAX_PENDING_REACTIONS: usize = 1000;
+//Same for edits
+const MAX_PENDING_EDITS: usize = 1000;
+//Deletions of messages not received yet, further ones are dropped
+const MAX_PENDING_DELETIONS: usize = 1000;
+
 #[derive(Debug, Error)]
 pub enum ConversationsError {
     #[error("Adding new message failed")]
     AddMessageFailed,
+    #[error("Decrypting message failed: `{0}`")]
+    DecryptionFailed(String),
     #[error("SendError")]
     SendError,
 }
 #[derive(Clone)]
 pub enum ConvsNotifications {
     NewMessage(Message),
+    NewGroupMessage {
+        group_id: String,
+        message: Message,
+    },
+    GroupUpdated(Group),
+    ContactUpdated(Contact),
+    ContactRemoved(Contact),
+    //`conversation_id` is the contact PK or the group id
+    MessageDeleted {
+        conversation_id: String,
+        message_id: String,
+    },
+    ReactionAdded {
+        conversation_id: String,
+        message_id: String,
+        pubkey: PublicKey,
+        reaction: String,
+    },
+    //A reply received before the message it quotes
+    QuoteResolved {
+        conversation_id: String,
+        message_id: String,
+        quoted: String,
+    },
+    MessageEdited {
+        conversation_id: String,
+        message: Message,
+    },
 }
 
 pub struct Conversations {
     convs: HashMap<String, Conversation>,
+    groups: HashMap<String, GroupConversation>,
+    //Ids of the received events, gift wraps included, and the conversation
+    //they were added to
+    event_ids: HashMap<String, String>,
+    //Ids of deleted messages and their author, so they are not added again
+    //if relays send them after the deletion
+    deleted: HashSet<(String, PublicKey)>,
+    //Reactions to messages not received yet, by message id
+    pending_reactions: HashMap<String, Vec<(PublicKey, String)>>,
+    //Edits of messages not received yet, by message id, with the
+    //conversation they were received in and their author
+    pending_edits: HashMap<String, Vec<(String, PublicKey, MessageEdit)>>,
     conv_noti_sender: broadcast::Sender<ConvsNotifications>,
     conv_noti_receiver: broadcast::Receiver<ConvsNotifications>,
 }
@@ -32,6 +93,11 @@ impl Conversations {
         let (sender, receiver) = broadcast::channel(64);
         Self {
             convs: HashMap::new(),
+            groups: HashMap::new(),
+            event_ids: HashMap::new(),
+            deleted: HashSet::new(),
+            pending_reactions: HashMap::new(),
+            pending_edits: HashMap::new(),
             conv_noti_sender: sender,
             conv_noti_receiver: receiver,
         }
@@ -41,58 +107,177 @@ impl Conversations {
         self.conv_noti_sender.subscribe()
     }
 
+    //Returns the group if the message created or renamed one
     pub fn try_add_message_from_ev(
         &mut self,
         ev: Event,
         user: &User,
-    ) -> Result<(), ConversationsError> {
+    ) -> Result<Option<Group>, ConversationsError> {
+        let ev_id = ev.id.to_string();
+        if self.contains_event(&ev_id) {
+            return Ok(None);
+        }
+        let sk = user.get_sk().ok_or(ConversationsError::AddMessageFailed)?;
         let peer_pk;
-        let source;
-        //If the user is event's author, is necessary get peer PK from p tag
-        //to decrypt
-        if ev.pubkey == user.get_pk() {
-            // TODO
-            // Currently this check is unecessary since
-            // will not be possible to receive other kind of events
-            //
-            //      if ev.tags.len() >= 1 {
-            //          if ev.tags[0].kind() == "p" {
-            //              peer_pk = PublicKey::from_str(ev.tags[0].content()).unwrap();
-            //          }
-            //      }
-            //
-
-            peer_pk = PublicKey::from_str(ev.tags[0].content()).unwrap();
-            source = MessageSource::Me;
-        } else {
-            peer_pk = ev.pubkey;
-            source = MessageSource::Them;
-        }
-        if let Some(conv) = self.get_mut_conv(&peer_pk.to_string()) {
-            if let Some(sk) = user.get_sk() {
-                match decrypt(&sk, &peer_pk, &ev.content) {
-                    Ok(decrypted_msg) => {
-                        let new_msg = Message::new(source, &decrypted_msg, ev);
-
-                        conv.add_message(new_msg.clone());
-
-                        //Send notification to listeners
-                        self.conv_noti_sender
-                            .send(ConvsNotifications::NewMessage(new_msg))
-                            .map_err(|_e| ConversationsError::SendError)?;
-                        Ok(())
-                    }
-                    Err(e) => {
-                        error!("{}", e);
-                        Err(ConversationsError::AddMessageFailed)
-                    }
+        let content;
+        let msg_ev;
+        if get_kind(&ev) == KIND_GIFT_WRAP {
+            //Gift wraps are addressed to us, the real author is inside the seal
+            let rumor = unwrap_gift(&sk, &ev).map_err(|e| {
+                error!("{}", e);
+                ConversationsError::DecryptionFailed(e.to_string())
+            })?;
+            //Deletions of private messages are wrapped like them
+            if rumor.kind == KIND_DELETION {
+                self.apply_deletion(&rumor.pubkey, &rumor.get_tag_values("e"))?;
+                return Ok(None);
+            }
+            if rumor.kind == KIND_REACTION {
+                if let Some((message_id, reaction)) = parse_reaction(&rumor) {
+                    self.add_reaction(&message_id, rumor.pubkey, reaction, &user.get_pk())?;
                 }
+                return Ok(None);
+            }
+            if rumor.kind != KIND_PRIVATE_DM {
+                return Err(ConversationsError::AddMessageFailed);
+            }
+            let participants = get_rumor_participants(&rumor, &user.get_pk());
+            if participants.len() > 1 {
+                return self.add_group_message(ev_id, rumor, participants, user);
+            }
+            peer_pk = *participants
+                .first()
+                .ok_or(ConversationsError::AddMessageFailed)?;
+            content = rumor.content.clone();
+            msg_ev = rumor;
+        } else {
+            //If the user is event's author, is necessary get peer PK from p tag
+            //to decrypt
+            if ev.pubkey == user.get_pk() {
+                let p = get_tag_values(&ev, "p");
+                let p = p.first().ok_or(ConversationsError::AddMessageFailed)?;
+                peer_pk =
+                    PublicKey::from_str(p).map_err(|_e| ConversationsError::AddMessageFailed)?;
             } else {
-                Err(ConversationsError::AddMessageFailed)
+                peer_pk = ev.pubkey;
             }
+            content = decrypt_content(&sk, &peer_pk, &ev.content).map_err(|e| {
+                error!("{}", e);
+                e
+            })?;
+            msg_ev = UnsignedEvent::from_event(&ev)
+                .map_err(|_e| ConversationsError::AddMessageFailed)?;
+        }
+
+        if self.is_deleted(&msg_ev) {
+            self.event_ids.insert(ev_id, peer_pk.to_string());
+            return Ok(None);
+        }
+        if let Some(message_id) = get_edit_of(&msg_ev.tags) {
+            let edit = MessageEdit {
+                content,
+                created_at: msg_ev.created_at,
+            };
+            self.apply_edit(&peer_pk.to_string(), &message_id, msg_ev.pubkey, edit)?;
+            self.event_ids.insert(ev_id, peer_pk.to_string());
+            return Ok(None);
+        }
+        let source = if msg_ev.pubkey == user.get_pk() {
+            MessageSource::Me
         } else {
-            Err(ConversationsError::AddMessageFailed)
+            MessageSource::Them
+        };
+        let conv = self
+            .get_mut_conv(&peer_pk.to_string())
+            .ok_or(ConversationsError::AddMessageFailed)?;
+        let new_msg = Message::new(source, &content, msg_ev);
+
+        let (new_msg, resolved) = conv.add_message(new_msg);
+        self.event_ids.insert(ev_id, peer_pk.to_string());
+
+        //Send notification to listeners
+        self.conv_noti_sender
+            .send(ConvsNotifications::NewMessage(new_msg.clone()))
+            .map_err(|_e| ConversationsError::SendError)?;
+        self.send_resolved_quotes(&peer_pk.to_string(), resolved)?;
+        self.add_pending_reactions(&new_msg.ev.id, &user.get_pk())?;
+        self.add_pending_edits(&peer_pk.to_string(), &new_msg.ev.id)?;
+        Ok(None)
+    }
+
+    fn add_group_message(
+        &mut self,
+        ev_id: String,
+        rumor: UnsignedEvent,
+        participants: Vec<PublicKey>,
+        user: &User,
+    ) -> Result<Option<Group>, ConversationsError> {
+        let group_id = Group::gen_id(&participants);
+        let mut group_updated = false;
+        if !self.groups.contains_key(&group_id) {
+            //Only contacts can add us to new groups
+            if !self.convs.contains_key(&rumor.pubkey.to_string()) {
+                return Err(ConversationsError::AddMessageFailed);
+            }
+            self.add_group_conv(GroupConversation::new(Group::new(participants, None)));
+            group_updated = true;
         }
+        let deleted = self.is_deleted(&rumor);
+        let edit_of = get_edit_of(&rumor.tags);
+        let conv = self
+            .groups
+            .get_mut(&group_id)
+            .ok_or(ConversationsError::AddMessageFailed)?;
+
+        //The newest subject in the room names the group
+        if let Some(subject) = rumor.get_tag_values("subject").first() {
+            if rumor.created_at >= conv.subject_updated_at
+                && conv.group.subject.as_ref() != Some(subject)
+            {
+                conv.group.subject = Some(subject.clone());
+                conv.subject_updated_at = rumor.created_at;
+                group_updated = true;
+            }
+        }
+        let group = conv.group.clone();
+        self.event_ids.insert(ev_id, group_id.clone());
+
+        //Renames are sent as messages without content
+        if !rumor.content.is_empty() && !deleted && edit_of.is_none() {
+            let source = if rumor.pubkey == user.get_pk() {
+                MessageSource::Me
+            } else {
+                MessageSource::Them
+            };
+            let new_msg = Message::new(source, &rumor.content.clone(), rumor);
+            let (new_msg, resolved) = conv.add_message(new_msg);
+
+            self.conv_noti_sender
+                .send(ConvsNotifications::NewGroupMessage {
+                    group_id: group_id.clone(),
+                    message: new_msg.clone(),
+                })
+                .map_err(|_e| ConversationsError::SendError)?;
+            self.send_resolved_quotes(&group_id, resolved)?;
+            self.add_pending_reactions(&new_msg.ev.id, &user.get_pk())?;
+            self.add_pending_edits(&group_id, &new_msg.ev.id)?;
+        } else if let Some(message_id) = edit_of {
+            if !rumor.content.is_empty() && !deleted {
+                let edit = MessageEdit {
+                    content: rumor.content.clone(),
+                    created_at: rumor.created_at,
+                };
+                self.apply_edit(&group_id, &message_id, rumor.pubkey, edit)?;
+            }
+        }
+
+        if group_updated {
+            self.conv_noti_sender
+                .send(ConvsNotifications::GroupUpdated(group.clone()))
+                .map_err(|_e| ConversationsError::SendError)?;
+            return Ok(Some(group));
+        }
+        Ok(None)
     }
 
     pub fn add_conv(&mut self, conv: Conversation) {
@@ -100,8 +285,14 @@ impl Conversations {
         self.convs.insert(pk, conv);
     }
 
-    pub fn remove_conv(&mut self, pk: &str) {
-        self.convs.remove(pk);
+    pub fn remove_conv(&mut self, pk: &str) -> Result<(), ConversationsError> {
+        if let Some(conv) = self.convs.remove(pk) {
+            self.event_ids.retain(|_, conv_id| conv_id != pk);
+            self.conv_noti_sender
+                .send(ConvsNotifications::ContactRemoved(conv.contact))
+                .map_err(|_e| ConversationsError::SendError)?;
+        }
+        Ok(())
     }
 
     pub fn get_conv(&self, pk: &str) -> Option<&Conversation> {
@@ -112,9 +303,296 @@ impl Conversations {
         self.convs.get_mut::<String>(&String::from(pk))
     }
 
+    //Adds the conversation if the contact is new
+    pub fn update_contact(&mut self, contact: Contact) -> Result<(), ConversationsError> {
+        match self.get_mut_conv(&contact.pk.to_string()) {
+            Some(conv) => conv.contact = contact.clone(),
+            None => self.add_conv(Conversation::new(contact.clone())),
+        }
+        self.conv_noti_sender
+            .send(ConvsNotifications::ContactUpdated(contact))
+            .map_err(|_e| ConversationsError::SendError)?;
+        Ok(())
+    }
+
     pub fn list_convs(&self) -> Vec<Conversation> {
         self.convs.iter().map(|(_k, v)| v.to_owned()).collect()
     }
+
+    pub fn add_group_conv(&mut self, conv: GroupConversation) {
+        self.groups.insert(conv.group.id.clone(), conv);
+    }
+
+    //Participants changes give the group a new id, messages are kept
+    pub fn replace_group(&mut self, old_group_id: &str, group: Group) {
+        let mut conv = self
+            .groups
+            .remove(old_group_id)
+            .unwrap_or_else(|| GroupConversation::new(group.clone()));
+        for conv_id in self.event_ids.values_mut() {
+            if conv_id == old_group_id {
+                *conv_id = group.id.clone();
+            }
+        }
+        conv.group = group;
+        self.add_group_conv(conv);
+    }
+
+    pub fn contains_event(&self, id: &str) -> bool {
+        self.event_ids.contains_key(id)
+    }
+
+    //The message and the id of its conversation
+    pub fn get_message(&self, id: &str) -> Option<(String, &Message)> {
+        let in_convs = self.convs.iter().map(|(pk, conv)| (pk, &conv.messages));
+        let in_groups = self
+            .groups
+            .iter()
+            .map(|(group_id, conv)| (group_id, &conv.messages));
+        in_convs.chain(in_groups).find_map(|(conv_id, messages)| {
+            messages
+                .iter()
+                .find(|m| m.ev.id == id)
+                .map(|m| (conv_id.clone(), m))
+        })
+    }
+
+    //NIP-09 deletion of `ids`. Only messages written by `author` are removed.
+    //Ids of messages not received yet are remembered for that author only
+    pub fn apply_deletion(
+        &mut self,
+        author: &PublicKey,
+        ids: &[String],
+    ) -> Result<(), ConversationsError> {
+        for id in ids {
+            match self.get_message(id) {
+                Some((_, message)) if message.ev.pubkey != *author => continue,
+                Some(_) => {
+                    self.pending_reactions.remove(id);
+                }
+                None if self.deleted.len() >= MAX_PENDING_DELETIONS => continue,
+                None => {
+                    if let Some(pending) = self.pending_edits.get_mut(id) {
+                        pending.retain(|(_, edit_author, _)| edit_author != author);
+                    }
+                }
+            }
+            self.deleted.insert((id.clone(), *author));
+            let is_deleted = |m: &Message| m.ev.id == *id && m.ev.pubkey == *author;
+            let in_convs = self
+                .convs
+                .iter_mut()
+                .map(|(pk, conv)| (pk, &mut conv.messages));
+            let in_groups = self
+                .groups
+                .iter_mut()
+                .map(|(group_id, conv)| (group_id, &mut conv.messages));
+            let mut deleted_from = vec![];
+            for (conv_id, messages) in in_convs.chain(in_groups) {
+                let len = messages.len();
+                messages.retain(|m| !is_deleted(m));
+                if messages.len() != len {
+                    deleted_from.push(conv_id.clone());
+                }
+            }
+            for conversation_id in deleted_from {
+                self.conv_noti_sender
+                    .send(ConvsNotifications::MessageDeleted {
+                        conversation_id,
+                        message_id: id.clone(),
+                    })
+                    .map_err(|_e| ConversationsError::SendError)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_resolved_quotes(
+        &self,
+        conversation_id: &str,
+        resolved: Vec<(String, String)>,
+    ) -> Result<(), ConversationsError> {
+        for (message_id, quoted) in resolved {
+            self.conv_noti_sender
+                .send(ConvsNotifications::QuoteResolved {
+                    conversation_id: conversation_id.into(),
+                    message_id,
+                    quoted,
+                })
+                .map_err(|_e| ConversationsError::SendError)?;
+        }
+        Ok(())
+    }
+
+    //Only participants of the message's conversation can react to it.
+    //Reactions to messages not received yet wait for them
+    pub fn add_reaction(
+        &mut self,
+        message_id: &str,
+        pubkey: PublicKey,
+        reaction: String,
+        user_pk: &PublicKey,
+    ) -> Result<(), ConversationsError> {
+        let in_convs = self
+            .convs
+            .iter_mut()
+            .map(|(pk, conv)| (pk, vec![conv.contact.pk], &mut conv.messages));
+        let in_groups = self.groups.iter_mut().map(|(group_id, conv)| {
+            (
+                group_id,
+                conv.group.participants.clone(),
+                &mut conv.messages,
+            )
+        });
+        let found = in_convs
+            .chain(in_groups)
+            .find_map(|(conv_id, participants, messages)| {
+                messages
+                    .iter_mut()
+                    .find(|m| m.ev.id == message_id)
+                    .map(|m| (conv_id.clone(), participants, m))
+            });
+        let (conversation_id, participants, message) = match found {
+            Some(found) => found,
+            None => {
+                //Only reactions from people we talk to wait for the message
+                if pubkey == *user_pk || self.is_participant(&pubkey) {
+                    if !self.pending_reactions.contains_key(message_id)
+                        && self.pending_reactions.len() >= MAX_PENDING_REACTIONS
+                    {
+                        return Ok(());
+                    }
+                    self.pending_reactions
+                        .entry(message_id.into())
+                        .or_default()
+                        .push((pubkey, reaction));
+                }
+                return Ok(());
+            }
+        };
+        if pubkey != *user_pk && !participants.contains(&pubkey) {
+            return Ok(());
+        }
+        let reactors = message.reactions.entry(reaction.clone()).or_default();
+        if reactors.contains(&pubkey) {
+            return Ok(());
+        }
+        reactors.push(pubkey);
+        self.conv_noti_sender
+            .send(ConvsNotifications::ReactionAdded {
+                conversation_id,
+                message_id: message_id.into(),
+                pubkey,
+                reaction,
+            })
+            .map_err(|_e| ConversationsError::SendError)?;
+        Ok(())
+    }
+
+    fn is_participant(&self, pk: &PublicKey) -> bool {
+        self.convs.contains_key(&pk.to_string())
+            || self
+                .groups
+                .values()
+                .any(|conv| conv.group.participants.contains(pk))
+    }
+
+    fn add_pending_reactions(
+        &mut self,
+        message_id: &str,
+        user_pk: &PublicKey,
+    ) -> Result<(), ConversationsError> {
+        if let Some(pending) = self.pending_reactions.remove(message_id) {
+            for (pubkey, reaction) in pending {
+                self.add_reaction(message_id, pubkey, reaction, user_pk)?;
+            }
+        }
+        Ok(())
+    }
+
+    //Only the author of a message can edit it, from the same conversation.
+    //Edits of messages not received yet wait for them
+    pub fn apply_edit(
+        &mut self,
+        conversation_id: &str,
+        message_id: &str,
+        author: PublicKey,
+        edit: MessageEdit,
+    ) -> Result<(), ConversationsError> {
+        let messages = if let Some(conv) = self.convs.get_mut(conversation_id) {
+            &mut conv.messages
+        } else if let Some(conv) = self.groups.get_mut(conversation_id) {
+            &mut conv.messages
+        } else {
+            return Err(ConversationsError::AddMessageFailed);
+        };
+        let message = match messages.iter_mut().find(|m| m.ev.id == message_id) {
+            Some(message) => message,
+            None => {
+                let full = !self.pending_edits.contains_key(message_id)
+                    && self.pending_edits.len() >= MAX_PENDING_EDITS;
+                if !full && !self.deleted.contains(&(message_id.to_string(), author)) {
+                    self.pending_edits
+                        .entry(message_id.into())
+                        .or_default()
+                        .push((conversation_id.into(), author, edit));
+                }
+                return Ok(());
+            }
+        };
+        if message.ev.pubkey != author || !message.apply_edit(edit) {
+            return Ok(());
+        }
+        let message = message.clone();
+        self.conv_noti_sender
+            .send(ConvsNotifications::MessageEdited {
+                conversation_id: conversation_id.into(),
+                message,
+            })
+            .map_err(|_e| ConversationsError::SendError)?;
+        Ok(())
+    }
+
+    fn add_pending_edits(
+        &mut self,
+        conversation_id: &str,
+        message_id: &str,
+    ) -> Result<(), ConversationsError> {
+        if let Some(pending) = self.pending_edits.remove(message_id) {
+            for (conv_id, author, edit) in pending {
+                if conv_id == conversation_id {
+                    self.apply_edit(&conv_id, message_id, author, edit)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_deleted(&self, msg_ev: &UnsignedEvent) -> bool {
+        self.deleted.contains(&(msg_ev.id.clone(), msg_ev.pubkey))
+    }
+
+    pub fn get_group_conv(&self, group_id: &str) -> Option<&GroupConversation> {
+        self.groups.get(group_id)
+    }
+}
+
+//Received DMs can be encrypted with NIP-04 or NIP-44 regardless of the scheme
+//we prefer for sending, so the payload format is detected for every message
+fn decrypt_content(
+    sk: &SecretKey,
+    peer_pk: &PublicKey,
+    content: &str,
+) -> Result<String, ConversationsError> {
+    if nip44::is_nip44_payload(content) {
+        let conversation_key = nip44::get_conversation_key(sk, peer_pk)
+            .map_err(|e| ConversationsError::DecryptionFailed(e.to_string()))?;
+        nip44::decrypt(&conversation_key, content)
+            .map_err(|e| ConversationsError::DecryptionFailed(e.to_string()))
+    } else {
+        decrypt(sk, peer_pk, &content.to_string())
+            .map_err(|e| ConversationsError::DecryptionFailed(e.to_string()))
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -131,11 +609,12 @@ impl Conversation {
         }
     }
 
-    fn add_message(&mut self, message: Message) {
+    //The added message, with its quote, and the earlier replies to it
+    fn add_message(&mut self, mut message: Message) -> (Message, Vec<(String, String)>) {
         // If the message is from Them, and is less than one minute old, show an OS notification
         let current_time = chrono::offset::Utc::now();
 
-        if message.ev.created_at > current_time - Duration::seconds(60) {
+        if message.ev.created_at > (current_time - Duration::seconds(60)).timestamp() {
             if message.source == MessageSource::Them {
                 //         if Notification::new()
                 //             .summary(&self.contact.alias)
@@ -148,27 +627,127 @@ impl Conversation {
             }
         }
 
-        self.messages.push(message);
+        let resolved = link_replies(&mut self.messages, &mut message);
+        self.messages.push(message.clone());
         self.messages
             .sort_by(|a, b| a.ev.created_at.cmp(&b.ev.created_at));
+        (message, resolved)
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupConversation {
+    pub group: Group,
+    pub messages: Vec<Message>,
+    #[serde(skip)]
+    subject_updated_at: i64,
+}
+
+impl GroupConversation {
+    pub fn new(group: Group) -> Self {
+        Self {
+            group,
+            messages: vec![],
+            subject_updated_at: 0,
+        }
+    }
+
+    fn add_message(&mut self, mut message: Message) -> (Message, Vec<(String, String)>) {
+        let resolved = link_replies(&mut self.messages, &mut message);
+        self.messages.push(message.clone());
+        self.messages.sort_by_key(|m| m.ev.created_at);
+        (message, resolved)
+    }
+}
+
+//Quotes the message `message` replies to, and fills the quote of the
+//replies to `message` that arrived before it. Returns the ids of those
+//replies with their quote
+fn link_replies(messages: &mut [Message], message: &mut Message) -> Vec<(String, String)> {
+    if let Some(reply_to) = &message.reply_to {
+        message.quoted = messages
+            .iter()
+            .find(|m| m.ev.id == *reply_to)
+            .map(|m| m.content.clone());
+    }
+    messages
+        .iter_mut()
+        .filter(|m| m.quoted.is_none() && m.reply_to.as_deref() == Some(message.ev.id.as_str()))
+        .map(|m| {
+            m.quoted = Some(message.content.clone());
+            (m.ev.id.clone(), message.content.clone())
+        })
+        .collect()
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
     pub source: MessageSource,
     pub content: String,
-    ev: Event,
+    ev: UnsignedEvent,
+    //Who reacted, by reaction
+    #[serde(default)]
+    pub reactions: BTreeMap<String, Vec<PublicKey>>,
+    //Id of the message this one replies to, and its content once received
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    #[serde(default)]
+    pub quoted: Option<String>,
+    //Earlier contents, oldest first, and when the content was last edited
+    #[serde(default)]
+    pub history: Vec<MessageEdit>,
+    #[serde(default)]
+    pub edited_at: Option<i64>,
 }
 
 impl Message {
-    pub fn new(source: MessageSource, content: &str, ev: Event) -> Self {
+    pub fn new(source: MessageSource, content: &str, ev: UnsignedEvent) -> Self {
         Self {
             source,
             content: content.into(),
+            reply_to: get_reply_to(&ev.tags),
             ev,
+            reactions: BTreeMap::new(),
+            quoted: None,
+            history: vec![],
+            edited_at: None,
         }
     }
+
+    //Edits can arrive in any order, the newest one is the content. Returns
+    //false for edits older than the message
+    fn apply_edit(&mut self, edit: MessageEdit) -> bool {
+        if edit.created_at < self.ev.created_at {
+            return false;
+        }
+        let current_at = self.edited_at.unwrap_or(self.ev.created_at);
+        if edit.created_at >= current_at {
+            let previous = MessageEdit {
+                content: std::mem::replace(&mut self.content, edit.content),
+                created_at: current_at,
+            };
+            self.history.push(previous);
+            self.edited_at = Some(edit.created_at);
+        } else {
+            let position = self
+                .history
+                .iter()
+                .position(|e| e.created_at > edit.created_at)
+                .unwrap_or(self.history.len());
+            self.history.insert(position, edit);
+        }
+        true
+    }
+
+    pub fn get_ev(&self) -> &UnsignedEvent {
+        &self.ev
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub content: String,
+    pub created_at: i64,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -176,3 +755,255 @@ pub enum MessageSource {
     Me,
     Them,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::nip10::reply_tag;
+    use nostr::Keys;
+
+    fn new_pk() -> PublicKey {
+        Keys::generate_from_os_random().unwrap().public_key
+    }
+
+    fn message(author: PublicKey, tags: Vec<Vec<String>>, content: &str) -> Message {
+        let ev = UnsignedEvent::new(author, KIND_PRIVATE_DM, tags, content);
+        Message::new(MessageSource::Them, content, ev)
+    }
+
+    //A conversation with `peer` holding `messages`
+    fn convs_with(peer: PublicKey, messages: Vec<Message>) -> Conversations {
+        let mut convs = Conversations::new();
+        let mut conv = Conversation::new(Contact::new("peer", peer));
+        for message in messages {
+            conv.add_message(message);
+        }
+        convs.add_conv(conv);
+        convs
+    }
+
+    #[test]
+    fn deletes_messages_of_the_author() {
+        let peer = new_pk();
+        let msg = message(peer, vec![], "hello");
+        let mut convs = convs_with(peer, vec![msg.clone()]);
+
+        convs
+            .apply_deletion(&peer, std::slice::from_ref(&msg.ev.id))
+            .unwrap();
+        assert!(convs.get_message(&msg.ev.id).is_none());
+        //Relays sending it again don't bring it back
+        assert!(convs.is_deleted(&msg.ev));
+    }
+
+    #[test]
+    fn ignores_deletions_of_others_messages() {
+        let peer = new_pk();
+        let msg = message(peer, vec![], "hello");
+        let mut convs = convs_with(peer, vec![msg.clone()]);
+
+        convs
+            .apply_deletion(&new_pk(), std::slice::from_ref(&msg.ev.id))
+            .unwrap();
+        assert!(convs.get_message(&msg.ev.id).is_some());
+        assert!(convs.deleted.is_empty());
+    }
+
+    #[test]
+    fn others_deletions_dont_undo_the_authors() {
+        let peer = new_pk();
+        let msg = message(peer, vec![], "hello");
+        let mut convs = convs_with(peer, vec![]);
+
+        //Deletions can arrive before the message
+        convs
+            .apply_deletion(&peer, std::slice::from_ref(&msg.ev.id))
+            .unwrap();
+        convs
+            .apply_deletion(&new_pk(), std::slice::from_ref(&msg.ev.id))
+            .unwrap();
+        assert!(convs.is_deleted(&msg.ev));
+    }
+
+    #[test]
+    fn bounds_deletions_of_unknown_messages() {
+        let peer = new_pk();
+        let msg = message(peer, vec![], "hello");
+        let mut convs = convs_with(peer, vec![msg.clone()]);
+
+        let stranger = new_pk();
+        let ids: Vec<String> = (0..MAX_PENDING_DELETIONS + 10)
+            .map(|i| i.to_string())
+            .collect();
+        convs.apply_deletion(&stranger, &ids).unwrap();
+        assert_eq!(convs.deleted.len(), MAX_PENDING_DELETIONS);
+
+        //Received messages can still be deleted
+        convs
+            .apply_deletion(&peer, std::slice::from_ref(&msg.ev.id))
+            .unwrap();
+        assert!(convs.get_message(&msg.ev.id).is_none());
+        assert!(convs.is_deleted(&msg.ev));
+    }
+
+    #[test]
+    fn adds_reactions_of_participants() {
+        let (user, peer) = (new_pk(), new_pk());
+        let msg = message(peer, vec![], "hello");
+        let mut convs = convs_with(peer, vec![msg.clone()]);
+        let id = &msg.ev.id;
+
+        convs.add_reaction(id, peer, "+".into(), &user).unwrap();
+        convs.add_reaction(id, peer, "+".into(), &user).unwrap();
+        convs.add_reaction(id, user, "+".into(), &user).unwrap();
+        convs.add_reaction(id, new_pk(), "+".into(), &user).unwrap();
+        let (_, msg) = convs.get_message(id).unwrap();
+        assert_eq!(msg.reactions["+"], vec![peer, user]);
+    }
+
+    #[test]
+    fn reactions_wait_for_their_message() {
+        let (user, peer) = (new_pk(), new_pk());
+        let msg = message(peer, vec![], "hello");
+        let mut convs = convs_with(peer, vec![]);
+        let id = &msg.ev.id;
+
+        convs.add_reaction(id, peer, "+".into(), &user).unwrap();
+        //Strangers' reactions are not kept
+        convs.add_reaction(id, new_pk(), "-".into(), &user).unwrap();
+        assert_eq!(convs.pending_reactions[id].len(), 1);
+
+        convs
+            .get_mut_conv(&peer.to_string())
+            .unwrap()
+            .add_message(msg.clone());
+        convs.add_pending_reactions(id, &user).unwrap();
+        let (_, msg) = convs.get_message(id).unwrap();
+        assert_eq!(msg.reactions.keys().collect::<Vec<_>>(), vec!["+"]);
+        assert!(convs.pending_reactions.is_empty());
+    }
+
+    #[test]
+    fn bounds_pending_reactions() {
+        let (user, peer) = (new_pk(), new_pk());
+        let mut convs = convs_with(peer, vec![]);
+        for i in 0..MAX_PENDING_REACTIONS + 10 {
+            convs
+                .add_reaction(&i.to_string(), peer, "+".into(), &user)
+                .unwrap();
+        }
+        assert_eq!(convs.pending_reactions.len(), MAX_PENDING_REACTIONS);
+        //Messages already waiting still get more
+        convs.add_reaction("0", user, "+".into(), &user).unwrap();
+        assert_eq!(convs.pending_reactions["0"].len(), 2);
+    }
+
+    #[test]
+    fn quotes_replies_in_any_order() {
+        let peer = new_pk();
+        let original = message(peer, vec![], "hello");
+        let reply = message(peer, vec![reply_tag(&original.ev.id)], "hi");
+        let mut conv = Conversation::new(Contact::new("peer", peer));
+
+        //The reply arrives first, its quote is filled with the original
+        let (reply, resolved) = conv.add_message(reply);
+        assert_eq!(reply.quoted, None);
+        assert!(resolved.is_empty());
+        let (_, resolved) = conv.add_message(original.clone());
+        assert_eq!(resolved, vec![(reply.ev.id.clone(), "hello".to_string())]);
+
+        let later = message(peer, vec![reply_tag(&original.ev.id)], "hey");
+        let (later, resolved) = conv.add_message(later);
+        assert_eq!(later.quoted.as_deref(), Some("hello"));
+        assert!(resolved.is_empty());
+        assert!(conv
+            .messages
+            .iter()
+            .filter(|m| m.reply_to.is_some())
+            .all(|m| m.quoted.as_deref() == Some("hello")));
+    }
+
+    fn edit(msg: &Message, content: &str, later: i64) -> MessageEdit {
+        MessageEdit {
+            content: content.into(),
+            created_at: msg.ev.created_at + later,
+        }
+    }
+
+    #[test]
+    fn applies_edits_of_the_author() {
+        let peer = new_pk();
+        let msg = message(peer, vec![], "helo");
+        let mut convs = convs_with(peer, vec![msg.clone()]);
+        let (conv_id, id) = (peer.to_string(), &msg.ev.id);
+
+        convs
+            .apply_edit(&conv_id, id, new_pk(), edit(&msg, "spam", 1))
+            .unwrap();
+        convs
+            .apply_edit(&conv_id, id, peer, edit(&msg, "hello", 2))
+            .unwrap();
+        //Older edits only go to the history
+        convs
+            .apply_edit(&conv_id, id, peer, edit(&msg, "hell", 1))
+            .unwrap();
+        let (_, edited) = convs.get_message(id).unwrap();
+        assert_eq!(edited.content, "hello");
+        assert_eq!(edited.edited_at, Some(msg.ev.created_at + 2));
+        let history: Vec<&str> = edited.history.iter().map(|e| e.content.as_str()).collect();
+        assert_eq!(history, vec!["helo", "hell"]);
+    }
+
+    #[test]
+    fn pending_edits_only_apply_in_their_conversation() {
+        let peer = new_pk();
+        let msg = message(peer, vec![], "helo");
+        let mut convs = convs_with(peer, vec![]);
+        let (conv_id, id) = (peer.to_string(), &msg.ev.id);
+
+        convs
+            .apply_edit(&conv_id, id, peer, edit(&msg, "hello", 1))
+            .unwrap();
+        assert_eq!(convs.pending_edits[id].len(), 1);
+
+        convs
+            .get_mut_conv(&conv_id)
+            .unwrap()
+            .add_message(msg.clone());
+        convs.add_pending_edits("other", id).unwrap();
+        assert_eq!(convs.get_message(id).unwrap().1.content, "helo");
+    }
+
+    #[test]
+    fn pending_edits_apply_when_the_message_arrives() {
+        let peer = new_pk();
+        let msg = message(peer, vec![], "helo");
+        let mut convs = convs_with(peer, vec![]);
+        let (conv_id, id) = (peer.to_string(), &msg.ev.id);
+
+        convs
+            .apply_edit(&conv_id, id, peer, edit(&msg, "hello", 1))
+            .unwrap();
+        convs
+            .get_mut_conv(&conv_id)
+            .unwrap()
+            .add_message(msg.clone());
+        convs.add_pending_edits(&conv_id, id).unwrap();
+        assert_eq!(convs.get_message(id).unwrap().1.content, "hello");
+        assert!(convs.pending_edits.is_empty());
+    }
+
+    #[test]
+    fn bounds_pending_edits() {
+        let peer = new_pk();
+        let msg = message(peer, vec![], "hello");
+        let mut convs = convs_with(peer, vec![]);
+        let conv_id = peer.to_string();
+        for i in 0..MAX_PENDING_EDITS + 10 {
+            convs
+                .apply_edit(&conv_id, &i.to_string(), peer, edit(&msg, "edit", 1))
+                .unwrap();
+        }
+        assert_eq!(convs.pending_edits.len(), MAX_PENDING_EDITS);
+    }
+}
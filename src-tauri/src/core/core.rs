@@ -1,7 +1,40 @@
 use super::{
-    config::{Config, ConfigProvider, Contact},
-    conversations::{Conversation, Conversations, ConvsNotifications},
-    relay_pool::{RelayPool, RelayPoolNotifications},
+    config::{
+        Config, ConfigProvider, Contact, EncryptionScheme, Group, Nip05Status, Nip05Verification,
+        RelayConfig,
+    },
+    conversations::{
+        Conversation, Conversations, ConvsNotifications, GroupConversation, MessageSource,
+    },
+    event::{
+        get_kind, get_tag_values, new_signed_event, new_signed_event_at, UnsignedEvent,
+        KIND_ENCRYPTED_DM,
+    },
+    event_cache::{EventCacheMetrics, EventStore},
+    http::{HttpClient, ReqwestClient},
+    nip02::{ContactListEntry, ContactListSync, KIND_CONTACT_LIST},
+    nip05::{Nip05Identifier, Nip05Resolver},
+    nip09::{deletion_tags, KIND_DELETION},
+    nip10::{edit_tag, reply_tag},
+    nip11::{fetch_relay_info, Nip11Error, RelayInformation, RelayInfos},
+    nip13::{
+        get_difficulty, mine_event, requested_difficulty, PowCanceller, PowError,
+        MAX_POW_DIFFICULTY,
+    },
+    nip17,
+    nip25::{parse_reaction, reaction_tags, KIND_REACTION},
+    nip42::new_auth_event,
+    nip44,
+    nip65::{
+        dm_relay_list_tags, parse_dm_relay_list, parse_relay_list, relay_list_tags, RelayLists,
+        KIND_DM_RELAY_LIST, KIND_RELAY_LIST,
+    },
+    profiles::{Profile, ProfileUpdate, Profiles, ProfilesNotifications, KIND_METADATA},
+    relay_pool::{
+        normalize_relay_url, RelayConnection, RelayPool, RelayPoolNotifications, RelayRejection,
+    },
+    socks::normalize_proxy,
+    subscription::Filter,
     user::User,
 };
 use log::{debug, error};
@@ -9,22 +42,58 @@ use nostr::Event;
 use secp256k1::schnorrsig::PublicKey;
 use std::{
     fmt::Debug,
+    str::FromStr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use thiserror::Error;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
+
+const NIP05_VERIFY_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+//Inbox relays of a contact we send to, besides ours
+const MAX_OUTBOX_RELAYS: usize = 4;
 
 //####### Core Task Handle Errors  #########
 #[derive(Debug, Error)]
 pub enum CoreTaskHandleError {
     #[error("Adding new relay failed")]
     AddRelayFailed,
+    #[error("Invalid relay URL, it must be ws:// or wss://")]
+    InvalidRelayUrl,
+    #[error("Invalid proxy, it must be host:port")]
+    InvalidProxy,
+    #[error("Updating proxy failed")]
+    UpdateProxyFailed,
+    #[error("Proof of work difficulty can't be over 32")]
+    InvalidPowDifficulty,
+    #[error("Updating proof of work difficulty failed")]
+    UpdatePowDifficultyFailed,
     #[error("Adding new contact failed")]
     AddContactFailed,
+    #[error("Contact already exists")]
+    ContactAlreadyExists,
     #[error("Removing new relay failed")]
     RemoveRelayFailed,
+    #[error("Updating relay failed")]
+    UpdateRelayFailed,
     #[error("Removing new contact failed")]
     RemoveContactFailed,
+    #[error("Updating contact failed")]
+    UpdateContactFailed,
+    #[error("Group not found")]
+    GroupNotFound,
+    #[error("Updating group failed")]
+    UpdateGroupFailed,
+    #[error("Groups need at least two other participants")]
+    NotEnoughParticipants,
+    #[error("Message not found")]
+    MessageNotFound,
+    #[error("Only our own messages can be deleted or edited")]
+    NotOwnMessage,
+    #[error("Reactions are a like or at most 32 characters")]
+    InvalidReaction,
+    #[error("Messages can't be empty")]
+    EmptyMessage,
 }
 
 //####### Core Task Handle  #########
@@ -40,17 +109,67 @@ pub enum CoreTaskHandleEvent {
 }
 
 pub struct CoreTaskHandle {
-    config: ConfigProvider,
+    config: Arc<Mutex<ConfigProvider>>,
     pub relay_pool: RelayPool,
     conversations: Arc<Mutex<Conversations>>,
+    profiles: Arc<Mutex<Profiles>>,
+    relay_lists: Arc<Mutex<RelayLists>>,
     user: Arc<Mutex<User>>,
+    nip05_resolver: Nip05Resolver,
+    contact_list: ContactListSync,
+    http_client: Arc<dyn HttpClient>,
+    relay_infos: Arc<Mutex<RelayInfos>>,
+    //Relays whose information was fetched in the background
+    relay_info_sender: mpsc::Sender<String>,
+    relay_info_receiver: Option<mpsc::Receiver<String>>,
+    //Events mined again in the background, with the relay that asked for it
+    mined_sender: mpsc::Sender<(String, Event)>,
+    mined_receiver: Option<mpsc::Receiver<(String, Event)>>,
+    pow_canceller: PowCanceller,
 }
 
 fn handle_notification(
     notification: RelayPoolNotifications,
     conversations: Arc<Mutex<Conversations>>,
     user: Arc<Mutex<User>>,
+    config: Arc<Mutex<ConfigProvider>>,
+    profiles: Arc<Mutex<Profiles>>,
+    relay_lists: Arc<Mutex<RelayLists>>,
 ) -> Result<(), String> {
+    if let RelayPoolNotifications::ReceivedEvent { ev } = &notification {
+        let kind = get_kind(ev);
+        if kind == KIND_RELAY_LIST || kind == KIND_DM_RELAY_LIST {
+            relay_lists
+                .lock()
+                .map_err(|_e| "Failed to get lock on relay lists".to_string())?
+                .try_update_from_ev(ev)
+                .map_err(|e| format!("Failed to update relay list: {}", e))?;
+            return Ok(());
+        }
+        if kind == KIND_METADATA {
+            profiles
+                .lock()
+                .map_err(|_e| "Failed to get lock on profiles".to_string())?
+                .try_update_from_ev(ev)
+                .map_err(|e| format!("Failed to update profile: {}", e))?;
+            return Ok(());
+        }
+        //Contact lists change the subscriptions, they are merged by the
+        //broker through `handle_relay_notification`
+        if kind == KIND_CONTACT_LIST {
+            return Ok(());
+        }
+        //Deletions of NIP-04 and NIP-44 DMs, NIP-17 ones come wrapped
+        if kind == KIND_DELETION {
+            conversations
+                .lock()
+                .map_err(|_e| "Failed to get lock on conversations".to_string())?
+                .apply_deletion(&ev.pubkey, &get_tag_values(ev, "e"))
+                .map_err(|e| format!("Failed to delete messages: {}", e))?;
+            return Ok(());
+        }
+    }
+
     let mut conversations = conversations
         .lock()
         .map_err(|_e| format!("Failed to get lock on conversations"))?;
@@ -61,33 +180,111 @@ fn handle_notification(
 
     match notification {
         RelayPoolNotifications::ReceivedEvent { ev } => {
-            conversations
+            //Groups created or renamed by other participants are persisted
+            if let Some(group) = conversations
                 .try_add_message_from_ev(ev, &user)
-                .map_err(|_e| format!("Failed to add message from ev"))?;
+                .map_err(|_e| format!("Failed to add message from ev"))?
+            {
+                config
+                    .lock()
+                    .map_err(|_e| "Failed to get lock on config".to_string())?
+                    .add_group(group)
+                    .map_err(|e| format!("Failed to save group: {}", e))?;
+            }
         }
         _ => (),
     };
     Ok(())
 }
 
+//Checks that contacts' NIP-05 identifiers still point to them
+async fn verify_contacts_nip05(
+    resolver: &Nip05Resolver,
+    config: Arc<Mutex<ConfigProvider>>,
+    conversations: Arc<Mutex<Conversations>>,
+) {
+    let contacts = config.lock().unwrap().list_contacts();
+    for contact in contacts {
+        let nip05 = match &contact.nip05 {
+            Some(nip05) => nip05,
+            None => continue,
+        };
+        let verified = match Nip05Identifier::from_str(&nip05.identifier) {
+            Ok(identifier) => resolver.verify(&identifier, &contact.pk).await,
+            Err(e) => Err(e),
+        };
+        //Network errors keep the last known status
+        let status = match verified {
+            Ok(true) => Nip05Status::Verified,
+            Ok(false) => Nip05Status::Mismatch,
+            Err(e) => {
+                error!("NIP-05 verification of {} failed: {}", nip05.identifier, e);
+                continue;
+            }
+        };
+        let pk = contact.pk.to_string();
+        let res = config
+            .lock()
+            .unwrap()
+            .set_contact_nip05(&pk, Some(Nip05Verification::new(&nip05.identifier, status)));
+        match res {
+            Ok(updated) if nip05.status != status => {
+                if let Err(e) = conversations.lock().unwrap().update_contact(updated) {
+                    error!("Failed to update contact {}: {}", pk, e);
+                }
+            }
+            Ok(_) => (),
+            Err(e) => error!("Failed to save NIP-05 status of {}: {}", pk, e),
+        }
+    }
+}
+
+//An empty proxy means none
+fn normalize_optional_proxy(proxy: Option<String>) -> Result<Option<String>, CoreTaskHandleError> {
+    match proxy {
+        Some(proxy) if !proxy.trim().is_empty() => normalize_proxy(&proxy)
+            .map(Some)
+            .map_err(|_| CoreTaskHandleError::InvalidProxy),
+        _ => Ok(None),
+    }
+}
+
 impl CoreTaskHandle {
-    pub fn new() -> Self {
+    pub fn new(pow_canceller: PowCanceller) -> Self {
         let config = ConfigProvider::load();
-        let mut relay_pool = RelayPool::new();
         let conversations = Arc::new(Mutex::new(Conversations::new()));
+        let mut relay_pool = RelayPool::new(
+            config.get_event_cache_size(),
+            Some(conversations.clone() as Arc<dyn EventStore>),
+            config.get_proxy(),
+        );
+        relay_pool.set_pow_difficulty(config.get_pow_difficulty());
+        let profiles = Arc::new(Mutex::new(Profiles::load()));
+        let relay_lists = Arc::new(Mutex::new(RelayLists::load()));
         let user = Arc::new(Mutex::new(User::new()));
 
-        for url in config.list_relays_url() {
-            relay_pool.add(&url);
+        for relay in config.list_relays() {
+            relay_pool.add(&relay);
         }
 
         for c in config.list_contacts() {
             conversations.lock().unwrap().add_conv(Conversation::new(c));
         }
 
+        for g in config.list_groups() {
+            conversations
+                .lock()
+                .unwrap()
+                .add_group_conv(GroupConversation::new(g));
+        }
+
+        let config = Arc::new(Mutex::new(config));
         let mut rec_ch = relay_pool.get_notifications_ch();
         let conversations_clone = conversations.clone();
         let user_clone = user.clone();
+        let config_clone = config.clone();
+        let profiles_clone = profiles.clone();
+        let relay_lists_clone = relay_lists.clone();
         tokio::spawn(async move {
             while let Ok(notification) = rec_ch.recv().await {
                 debug!("Received from broadcast {:?}", notification);
@@ -96,24 +293,363 @@ impl CoreTaskHandle {
                     notification,
                     conversations_clone.clone(),
                     user_clone.clone(),
+                    config_clone.clone(),
+                    profiles_clone.clone(),
+                    relay_lists_clone.clone(),
                 ) {
                     error!("Handle notification error: {}", e.to_string())
                 };
             }
         });
 
-        Self {
+        //NIP-05 and NIP-11 requests go through the proxy too
+        let proxy = config.lock().unwrap().get_proxy();
+        let http_client: Arc<dyn HttpClient> = Arc::new(ReqwestClient::new(proxy.as_deref()));
+        let nip05_resolver = Nip05Resolver::new(http_client.clone());
+        let resolver_clone = nip05_resolver.clone();
+        let config_clone = config.clone();
+        let conversations_clone = conversations.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(NIP05_VERIFY_INTERVAL);
+            loop {
+                interval.tick().await;
+                verify_contacts_nip05(
+                    &resolver_clone,
+                    config_clone.clone(),
+                    conversations_clone.clone(),
+                )
+                .await;
+            }
+        });
+
+        let (relay_info_sender, relay_info_receiver) = mpsc::channel(64);
+        let (mined_sender, mined_receiver) = mpsc::channel(64);
+        let core = Self {
             config,
             relay_pool,
             conversations,
+            profiles,
+            relay_lists,
             user,
+            nip05_resolver,
+            contact_list: ContactListSync::load(),
+            http_client,
+            relay_infos: Arc::new(Mutex::new(RelayInfos::load())),
+            relay_info_sender,
+            relay_info_receiver: Some(relay_info_receiver),
+            mined_sender,
+            mined_receiver: Some(mined_receiver),
+            pow_canceller,
+        };
+        for relay in core.list_relays() {
+            core.refresh_relay_info(&relay.url);
+        }
+        core
+    }
+
+    pub async fn handle_relay_notification(&mut self, notification: RelayPoolNotifications) {
+        match &notification {
+            RelayPoolNotifications::AuthChallenge {
+                relay_url,
+                challenge,
+            } => return self.authenticate(relay_url, challenge).await,
+            RelayPoolNotifications::EventOk {
+                relay_url,
+                event_id,
+                accepted,
+                message,
+                ev,
+            } => {
+                self.relay_pool
+                    .handle_ok(relay_url, event_id, *accepted, message, ev.clone())
+                    .await;
+                if let (false, Some(ev)) = (*accepted, ev) {
+                    self.retry_with_pow(relay_url, ev, message);
+                }
+                return;
+            }
+            _ => (),
+        }
+        if let RelayPoolNotifications::ReceivedEvent { ev } = notification {
+            let user_pk = self.user.lock().unwrap().get_pk();
+            if ev.pubkey != user_pk {
+                return;
+            }
+            match get_kind(&ev) {
+                KIND_CONTACT_LIST => {
+                    if let Err(e) = self.merge_contact_list(&ev).await {
+                        error!("Failed to merge contact list: {}", e);
+                    }
+                }
+                KIND_RELAY_LIST | KIND_DM_RELAY_LIST => {
+                    if let Err(e) = self.restore_relay_list(&ev).await {
+                        error!("Failed to restore relay list: {}", e);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    //Events we signed that were refused for lack of work are mined again at
+    //the asked difficulty, only for that relay. Gift wraps can't be, their
+    //keys are thrown away, the next ones are mined at that difficulty.
+    //Mining runs in the background, the broker sends the mined event with
+    //`send_mined`
+    fn retry_with_pow(&mut self, relay_url: &str, ev: &Event, message: &str) {
+        let difficulty = match requested_difficulty(message) {
+            Some(difficulty) => difficulty,
+            None => return,
+        };
+        self.relay_pool.set_required_pow(relay_url, difficulty);
+        let keys = {
+            let user = self.user.lock().unwrap();
+            if ev.pubkey != user.get_pk() {
+                return;
+            }
+            user.keys.clone()
+        };
+        if get_difficulty(ev) >= difficulty {
+            return;
+        }
+        let canceller = self.pow_canceller.clone();
+        let sender = self.mined_sender.clone();
+        let relay_url = relay_url.to_string();
+        let ev = ev.clone();
+        tokio::spawn(async move {
+            match mine_event(&keys, &ev, difficulty, &canceller).await {
+                Ok(mined) => {
+                    if let Err(e) = sender.send((relay_url, mined)).await {
+                        error!("mined_sender error: {}", e);
+                    }
+                }
+                Err(e) => error!("Failed to mine {} for {}: {}", ev.id, relay_url, e),
+            }
+        });
+    }
+
+    pub fn take_mined_ch(&mut self) -> Option<mpsc::Receiver<(String, Event)>> {
+        self.mined_receiver.take()
+    }
+
+    pub async fn send_mined(&mut self, relay_url: &str, ev: Event) {
+        self.relay_pool.send_ev_to_relay(relay_url, ev).await;
+    }
+
+    //Relays that opted out of NIP-42 only serve us what they serve anyone.
+    //Temporary relays, not in the config, authenticate by default
+    async fn authenticate(&mut self, relay_url: &str, challenge: &str) {
+        let relay = self.config.lock().unwrap().get_relay(relay_url);
+        let enabled = match relay {
+            Some(relay) => relay.auth,
+            None => self.relay_pool.is_temp_relay(relay_url),
+        };
+        if !enabled {
+            debug!("Ignoring AUTH challenge from {}", relay_url);
+            return;
+        }
+        let ev = {
+            let user = self.user.lock().unwrap();
+            new_auth_event(&user.keys, relay_url, challenge)
+        };
+        match ev {
+            Ok(ev) => self.relay_pool.send_auth(relay_url, ev).await,
+            Err(e) => error!("Failed to sign AUTH event for {}: {}", relay_url, e),
+        }
+    }
+
+    //Our relay lists published by another client, or found on relays
+    //after importing a key, update the relay config
+    async fn restore_relay_list(&mut self, ev: &Event) -> Result<(), std::io::Error> {
+        let created_at = ev.created_at.timestamp();
+        let (new_relays, removed_relays) = if get_kind(ev) == KIND_RELAY_LIST {
+            let (read, write) = parse_relay_list(ev);
+            self.config
+                .lock()
+                .unwrap()
+                .restore_relay_list(&read, &write, created_at)?
+        } else {
+            self.config
+                .lock()
+                .unwrap()
+                .restore_dm_relay_list(&parse_dm_relay_list(ev), created_at)?
+        };
+        for url in &removed_relays {
+            self.relay_pool.remove(url).await;
+        }
+        let relays = self.config.lock().unwrap().list_relays();
+        for relay in relays {
+            if new_relays.contains(&relay.url) {
+                self.relay_pool.add(&relay);
+                self.relay_pool.connect_relay(&relay.url).await;
+            } else {
+                self.relay_pool.update_roles(&relay).await;
+            }
+        }
+        Ok(())
+    }
+
+    //Publishes our NIP-65 and NIP-17 relay lists from the relay config
+    pub async fn publish_relay_lists(&mut self) {
+        let user = self.user.lock().unwrap().clone();
+        let relays = self.config.lock().unwrap().list_relays();
+        let created_at = chrono::offset::Utc::now().timestamp();
+
+        let mut evs = vec![];
+        for (kind, tags) in [
+            (KIND_RELAY_LIST, relay_list_tags(&relays)),
+            (KIND_DM_RELAY_LIST, dm_relay_list_tags(&relays)),
+        ] {
+            match new_signed_event_at(&user.keys, created_at, kind, tags, "") {
+                Ok(ev) => evs.push(ev),
+                Err(e) => {
+                    error!("Failed to sign relay list: {}", e);
+                    return;
+                }
+            }
+        }
+        if let Err(e) = self
+            .config
+            .lock()
+            .unwrap()
+            .set_relay_lists_published_at(created_at)
+        {
+            error!("Failed to save relay lists timestamp: {}", e);
+        }
+        for ev in evs {
+            self.relay_pool.send_ev(ev).await;
+        }
+    }
+
+    //Applies changes made to the contact list by other clients, local
+    //changes they don't know about are published back if enabled
+    async fn merge_contact_list(&mut self, ev: &Event) -> Result<(), Box<dyn std::error::Error>> {
+        let ev = UnsignedEvent::from_event(ev)?;
+        let local = self.config.lock().unwrap().list_contacts();
+        let merge = match self.contact_list.merge(&ev, &local) {
+            Some(merge) => merge,
+            None => return Ok(()),
+        };
+        let changed =
+            !merge.added.is_empty() || !merge.updated.is_empty() || !merge.removed.is_empty();
+
+        for entry in merge.added {
+            let contact = self.contact_from_entry(entry);
+            self.config.lock().unwrap().add_contact(contact.clone())?;
+            self.conversations.lock().unwrap().update_contact(contact)?;
+        }
+        for contact in merge.updated {
+            self.config.lock().unwrap().add_contact(contact.clone())?;
+            self.conversations.lock().unwrap().update_contact(contact)?;
         }
+        for contact in merge.removed {
+            self.config
+                .lock()
+                .unwrap()
+                .remove_contact(contact.clone())?;
+            self.conversations
+                .lock()
+                .unwrap()
+                .remove_conv(&contact.pk.to_string())?;
+            self.relay_pool.remove_contact_events(contact).await;
+        }
+
+        if changed {
+            self.subscribe().await;
+        }
+        if merge.needs_publish {
+            self.publish_contact_list().await;
+        }
+        Ok(())
+    }
+
+    fn contact_from_entry(&self, entry: ContactListEntry) -> Contact {
+        let pk = entry.pk.to_string();
+        let alias = entry
+            .petname
+            .or_else(|| {
+                self.get_profile(&pk)
+                    .and_then(|p| p.display_name.or(p.name))
+            })
+            .unwrap_or_else(|| pk[..8].to_string());
+        let mut contact = Contact::new(&alias, entry.pk);
+        contact.relay_hint = entry.relay_hint;
+        contact
+    }
+
+    pub async fn publish_contact_list(&mut self) {
+        if !self.contact_list.publish {
+            return;
+        }
+        let user = self.user.lock().unwrap().clone();
+        let local = self.config.lock().unwrap().list_contacts();
+        let (tags, content) = self.contact_list.build(&local);
+        let ev = match new_signed_event(&user.keys, KIND_CONTACT_LIST, tags, &content) {
+            Ok(ev) => ev,
+            Err(e) => {
+                error!("Failed to sign contact list: {}", e);
+                return;
+            }
+        };
+        match UnsignedEvent::from_event(&ev) {
+            Ok(published) => {
+                if let Err(e) = self.contact_list.set_published(published) {
+                    error!("Failed to save contact list: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to save contact list: {}", e),
+        }
+        self.relay_pool.send_ev(ev).await;
+    }
+
+    pub fn get_contact_list_publishing(&self) -> bool {
+        self.contact_list.publish
+    }
+
+    pub async fn set_contact_list_publishing(
+        &mut self,
+        publish: bool,
+    ) -> Result<(), CoreTaskHandleError> {
+        self.contact_list
+            .set_publish(publish)
+            .map_err(|_| CoreTaskHandleError::UpdateContactFailed)?;
+        self.publish_contact_list().await;
+        Ok(())
     }
 
     pub fn get_convs_notifications(&self) -> broadcast::Receiver<ConvsNotifications> {
         self.conversations.lock().unwrap().get_notifications_ch()
     }
 
+    pub fn get_profiles_notifications(&self) -> broadcast::Receiver<ProfilesNotifications> {
+        self.profiles.lock().unwrap().get_notifications_ch()
+    }
+
+    pub fn get_profile(&self, pk: &str) -> Option<Profile> {
+        self.profiles.lock().unwrap().get_profile(pk)
+    }
+
+    pub fn get_user_profile(&self) -> Option<Profile> {
+        let user_pk = self.user.lock().unwrap().get_pk();
+        self.get_profile(&user_pk.to_string())
+    }
+
+    pub async fn set_user_profile(
+        &mut self,
+        update: ProfileUpdate,
+    ) -> Result<Option<Profile>, Box<dyn std::error::Error + '_>> {
+        let user = self.user.lock()?.clone();
+        let content = self
+            .profiles
+            .lock()?
+            .build_metadata(&user.get_pk().to_string(), &update)?;
+        let ev = new_signed_event(&user.keys, KIND_METADATA, vec![], &content)?;
+
+        let profile = self.profiles.lock()?.try_update_from_ev(&ev)?;
+        self.relay_pool.send_ev(ev).await;
+        Ok(profile)
+    }
+
     pub fn get_conv(&self, pk: String) -> Option<Conversation> {
         Some(
             self.conversations
@@ -129,24 +665,458 @@ impl CoreTaskHandle {
         &mut self,
         contact_pk: &str,
         content: &str,
-    ) -> Result<(), Box<dyn std::error::Error + '_>> {
+        reply_to: Option<&str>,
+    ) -> Result<Vec<RelayRejection>, Box<dyn std::error::Error + '_>> {
+        //Replies reference the replied message with a marked `e` tag
+        let extra_tags = reply_to.map(reply_tag).into_iter().collect();
+        self.send_dm(contact_pk, content, extra_tags).await
+    }
+
+    async fn send_dm(
+        &self,
+        contact_pk: &str,
+        content: &str,
+        extra_tags: Vec<Vec<String>>,
+    ) -> Result<Vec<RelayRejection>, Box<dyn std::error::Error + '_>> {
         let user = self.user.lock()?.clone();
-        let ev = Event::new_encrypted_direct_msg(
-            &user.keys,
-            &nostr::Keys::new_pub_only(&contact_pk.to_string())?,
-            content,
-        )?;
+        let encryption = self
+            .config
+            .lock()?
+            .get_contact(contact_pk)
+            .map(|c| c.encryption)
+            .unwrap_or_default();
+        let peer_pk = PublicKey::from_str(contact_pk)?;
+        let nip17 = encryption == EncryptionScheme::Nip17;
+        let inbox_relays = self.get_inbox_relays(&peer_pk, nip17);
+        let mut tags = vec![vec!["p".into(), contact_pk.into()]];
+        tags.extend(extra_tags.clone());
+        //The first event is the one we can decrypt to add the message locally
+        let mut evs = match encryption {
+            EncryptionScheme::Nip04 => {
+                let sk = user.get_sk().ok_or("Missing secret key")?;
+                vec![new_signed_event(
+                    &user.keys,
+                    KIND_ENCRYPTED_DM,
+                    tags,
+                    &nostr::util::nip04::encrypt(&sk, &peer_pk, content),
+                )?]
+            }
+            EncryptionScheme::Nip44 => {
+                let sk = user.get_sk().ok_or("Missing secret key")?;
+                let conversation_key =
+                    nip44::get_conversation_key(&sk, &PublicKey::from_str(contact_pk)?)?;
+                vec![new_signed_event(
+                    &user.keys,
+                    KIND_ENCRYPTED_DM,
+                    tags,
+                    &nip44::encrypt(&conversation_key, content)?,
+                )?]
+            }
+            EncryptionScheme::Nip17 => {
+                let rumor = nip17::new_rumor(user.get_pk(), &[peer_pk], content, extra_tags);
+                //A copy is wrapped to ourselves so we can read our own
+                //messages back from relays
+                let self_difficulty = self.relay_pool.pow_difficulty(nip17::KIND_GIFT_WRAP, &[]);
+                let difficulty = self
+                    .relay_pool
+                    .pow_difficulty(nip17::KIND_GIFT_WRAP, &inbox_relays);
+                vec![
+                    nip17::gift_wrap(
+                        &user.keys,
+                        &user.get_pk(),
+                        &rumor,
+                        self_difficulty,
+                        &self.pow_canceller,
+                    )
+                    .await?,
+                    nip17::gift_wrap(
+                        &user.keys,
+                        &peer_pk,
+                        &rumor,
+                        difficulty,
+                        &self.pow_canceller,
+                    )
+                    .await?,
+                ]
+            }
+        };
+
+        //Relays asking for proof of work get it upfront, gift wraps are
+        //mined as they are wrapped
+        if !nip17 {
+            evs[0] = self
+                .mine_for(&user.keys, evs[0].clone(), &inbox_relays)
+                .await?;
+        }
+
+        //Messages no relay can take are rejected before showing them
+        let rejections = self
+            .relay_pool
+            .check_ev(&evs[evs.len() - 1], &inbox_relays)?;
 
         self.conversations
             .lock()?
-            .try_add_message_from_ev(ev.clone(), &user)?;
+            .try_add_message_from_ev(evs[0].clone(), &user)?;
 
         //This is necessary because we cant send a mutex to another thread
         //and Tokio runtime can move this task between threads at every .await
         //https://tokio.rs/tokio/tutorial/shared-state
         //
-        self.relay_pool.send_ev(ev).await;
+        //The event addressed to the contact, the last one, also goes to its
+        //inbox relays. A self wrapped copy only goes to ours
+        let last = evs.len() - 1;
+        for (i, ev) in evs.into_iter().enumerate() {
+            if i == last {
+                self.relay_pool.send_ev_to(ev, &inbox_relays).await;
+            } else {
+                self.relay_pool.send_ev(ev).await;
+            }
+        }
+
+        Ok(rejections)
+    }
+
+    //Events we sign get the work asked by the relays they go to
+    async fn mine_for(
+        &self,
+        keys: &nostr::Keys,
+        ev: Event,
+        relay_urls: &[String],
+    ) -> Result<Event, PowError> {
+        let difficulty = self.relay_pool.pow_difficulty(get_kind(&ev), relay_urls);
+        if difficulty == 0 {
+            return Ok(ev);
+        }
+        mine_event(keys, &ev, difficulty, &self.pow_canceller).await
+    }
+
+    //Relays where the contact reads its DMs, following the outbox model.
+    //NIP-17 messages prefer the DM relay list, falling back to the read
+    //relays and the contact list relay hint
+    fn get_inbox_relays(&self, pk: &PublicKey, nip17: bool) -> Vec<String> {
+        let list = self.relay_lists.lock().unwrap().get(pk).unwrap_or_default();
+        let mut urls = if nip17 && !list.dm.is_empty() {
+            list.dm
+        } else {
+            list.read
+        };
+        if urls.is_empty() {
+            let hint = self
+                .config
+                .lock()
+                .unwrap()
+                .get_contact(&pk.to_string())
+                .and_then(|c| c.relay_hint);
+            urls.extend(hint);
+        }
+        //Urls come from other users' events, invalid ones are dropped
+        let mut relays: Vec<String> = vec![];
+        for url in urls.iter().filter_map(|u| normalize_relay_url(u).ok()) {
+            if !relays.contains(&url) {
+                relays.push(url);
+            }
+        }
+        relays.truncate(MAX_OUTBOX_RELAYS);
+        relays
+    }
+
+    pub fn get_group_conv(&self, group_id: String) -> Option<GroupConversation> {
+        self.conversations
+            .lock()
+            .unwrap()
+            .get_group_conv(&group_id)
+            .cloned()
+    }
+
+    pub fn list_groups(&self) -> Vec<Group> {
+        self.config.lock().unwrap().list_groups()
+    }
+
+    pub fn create_group(
+        &mut self,
+        participants: Vec<PublicKey>,
+        subject: Option<String>,
+    ) -> Result<Group, CoreTaskHandleError> {
+        let group = self.new_group(participants, subject)?;
+        self.config
+            .lock()
+            .unwrap()
+            .add_group(group.clone())
+            .map_err(|_| CoreTaskHandleError::UpdateGroupFailed)?;
+        self.conversations
+            .lock()
+            .unwrap()
+            .add_group_conv(GroupConversation::new(group.clone()));
+        Ok(group)
+    }
 
+    pub async fn rename_group(
+        &mut self,
+        group_id: &str,
+        subject: String,
+    ) -> Result<(), Box<dyn std::error::Error + '_>> {
+        let mut group = self
+            .config
+            .lock()?
+            .get_group(group_id)
+            .ok_or(CoreTaskHandleError::GroupNotFound)?;
+        group.subject = Some(subject.clone());
+        self.config.lock()?.add_group(group.clone())?;
+
+        //Other participants learn the new subject from a message without content
+        self.send_group_rumor(&group, "", vec![vec!["subject".into(), subject]])
+            .await?;
+        Ok(())
+    }
+
+    pub fn add_group_participant(
+        &mut self,
+        group_id: &str,
+        pk: PublicKey,
+    ) -> Result<Group, CoreTaskHandleError> {
+        let group = self
+            .config
+            .lock()
+            .unwrap()
+            .get_group(group_id)
+            .ok_or(CoreTaskHandleError::GroupNotFound)?;
+        let mut participants = group.participants;
+        participants.push(pk);
+        self.replace_group_participants(group_id, participants, group.subject)
+    }
+
+    pub fn remove_group_participant(
+        &mut self,
+        group_id: &str,
+        pk: PublicKey,
+    ) -> Result<Group, CoreTaskHandleError> {
+        let group = self
+            .config
+            .lock()
+            .unwrap()
+            .get_group(group_id)
+            .ok_or(CoreTaskHandleError::GroupNotFound)?;
+        let mut participants = group.participants;
+        participants.retain(|p| p != &pk);
+        self.replace_group_participants(group_id, participants, group.subject)
+    }
+
+    fn replace_group_participants(
+        &mut self,
+        group_id: &str,
+        participants: Vec<PublicKey>,
+        subject: Option<String>,
+    ) -> Result<Group, CoreTaskHandleError> {
+        let group = self.new_group(participants, subject)?;
+        //The config guard is released first, notifications lock
+        //conversations before config
+        {
+            let mut config = self.config.lock().unwrap();
+            config
+                .remove_group(group_id)
+                .and_then(|_| config.add_group(group.clone()))
+                .map_err(|_| CoreTaskHandleError::UpdateGroupFailed)?;
+        }
+        self.conversations
+            .lock()
+            .unwrap()
+            .replace_group(group_id, group.clone());
+        Ok(group)
+    }
+
+    fn new_group(
+        &self,
+        mut participants: Vec<PublicKey>,
+        subject: Option<String>,
+    ) -> Result<Group, CoreTaskHandleError> {
+        let user_pk = self.user.lock().unwrap().get_pk();
+        participants.retain(|pk| pk != &user_pk);
+        let group = Group::new(participants, subject);
+        if group.participants.len() < 2 {
+            return Err(CoreTaskHandleError::NotEnoughParticipants);
+        }
+        Ok(group)
+    }
+
+    pub async fn send_msg_to_group(
+        &mut self,
+        group_id: &str,
+        content: &str,
+    ) -> Result<Vec<RelayRejection>, Box<dyn std::error::Error + '_>> {
+        let group = self
+            .config
+            .lock()?
+            .get_group(group_id)
+            .ok_or(CoreTaskHandleError::GroupNotFound)?;
+        let tags = match &group.subject {
+            Some(subject) => vec![vec!["subject".into(), subject.clone()]],
+            None => vec![],
+        };
+        self.send_group_rumor(&group, content, tags).await
+    }
+
+    async fn send_group_rumor(
+        &self,
+        group: &Group,
+        content: &str,
+        tags: Vec<Vec<String>>,
+    ) -> Result<Vec<RelayRejection>, Box<dyn std::error::Error + '_>> {
+        let user_pk = self.user.lock()?.get_pk();
+        let rumor = nip17::new_rumor(user_pk, &group.participants, content, tags);
+        self.send_rumor(&group.participants, &rumor).await
+    }
+
+    //The rumor is added locally through our self wrapped copy
+    async fn send_rumor(
+        &self,
+        participants: &[PublicKey],
+        rumor: &UnsignedEvent,
+    ) -> Result<Vec<RelayRejection>, Box<dyn std::error::Error + '_>> {
+        let user = self.user.lock()?.clone();
+        //Every participant gets its own gift wrapped copy, sent to its
+        //inbox relays too
+        let self_difficulty = self.relay_pool.pow_difficulty(nip17::KIND_GIFT_WRAP, &[]);
+        let self_wrap = nip17::gift_wrap(
+            &user.keys,
+            &user.get_pk(),
+            rumor,
+            self_difficulty,
+            &self.pow_canceller,
+        )
+        .await?;
+        let mut evs = vec![];
+        for pk in participants.iter() {
+            let inbox_relays = self.get_inbox_relays(pk, true);
+            let difficulty = self
+                .relay_pool
+                .pow_difficulty(nip17::KIND_GIFT_WRAP, &inbox_relays);
+            evs.push((
+                nip17::gift_wrap(&user.keys, pk, rumor, difficulty, &self.pow_canceller).await?,
+                inbox_relays,
+            ));
+        }
+        //Every participant has to be reachable
+        let mut rejections: Vec<RelayRejection> = vec![];
+        for (ev, inbox_relays) in evs.iter() {
+            for rejection in self.relay_pool.check_ev(ev, inbox_relays)? {
+                if !rejections
+                    .iter()
+                    .any(|r| r.relay_url == rejection.relay_url)
+                {
+                    rejections.push(rejection);
+                }
+            }
+        }
+
+        self.conversations
+            .lock()?
+            .try_add_message_from_ev(self_wrap.clone(), &user)?;
+
+        self.relay_pool.send_ev(self_wrap).await;
+        for (ev, inbox_relays) in evs {
+            self.relay_pool.send_ev_to(ev, &inbox_relays).await;
+        }
+        Ok(rejections)
+    }
+
+    //NIP-09 deletion of one of our messages. NIP-17 messages are deleted
+    //with a wrapped rumor, so their id is not revealed to relays
+    pub async fn delete_message(
+        &mut self,
+        message_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error + '_>> {
+        let user = self.user.lock()?.clone();
+        let (conv_id, kind) = {
+            let conversations = self.conversations.lock()?;
+            let (conv_id, message) = conversations
+                .get_message(message_id)
+                .ok_or(CoreTaskHandleError::MessageNotFound)?;
+            if message.source != MessageSource::Me {
+                return Err(Box::new(CoreTaskHandleError::NotOwnMessage));
+            }
+            (conv_id, message.get_ev().kind)
+        };
+        let group = self.config.lock()?.get_group(&conv_id);
+        let participants = match group {
+            Some(group) => group.participants,
+            None => vec![PublicKey::from_str(&conv_id)?],
+        };
+        let tags = deletion_tags(&[message_id.to_string()], kind, &participants);
+
+        if kind == nip17::KIND_PRIVATE_DM {
+            let rumor = UnsignedEvent::new(user.get_pk(), KIND_DELETION, tags, "");
+            self.send_rumor(&participants, &rumor).await?;
+            return Ok(());
+        }
+        let ev = new_signed_event(&user.keys, KIND_DELETION, tags, "")?;
+        let inbox_relays = self.get_inbox_relays(&participants[0], false);
+        let ev = self.mine_for(&user.keys, ev, &inbox_relays).await?;
+        self.conversations
+            .lock()?
+            .apply_deletion(&user.get_pk(), &[message_id.to_string()])?;
+        self.relay_pool.send_ev_to(ev, &inbox_relays).await;
+        Ok(())
+    }
+
+    //Edits are new messages referencing the one they replace with an `edit`
+    //marked `e` tag, sent like any message of its conversation
+    pub async fn edit_message(
+        &mut self,
+        message_id: &str,
+        content: &str,
+    ) -> Result<Vec<RelayRejection>, Box<dyn std::error::Error + '_>> {
+        if content.is_empty() {
+            return Err(Box::new(CoreTaskHandleError::EmptyMessage));
+        }
+        let conv_id = {
+            let conversations = self.conversations.lock()?;
+            let (conv_id, message) = conversations
+                .get_message(message_id)
+                .ok_or(CoreTaskHandleError::MessageNotFound)?;
+            if message.source != MessageSource::Me {
+                return Err(Box::new(CoreTaskHandleError::NotOwnMessage));
+            }
+            conv_id
+        };
+        let tags = vec![edit_tag(message_id)];
+        let group = self.config.lock()?.get_group(&conv_id);
+        match group {
+            Some(group) => self.send_group_rumor(&group, content, tags).await,
+            None => self.send_dm(&conv_id, content, tags).await,
+        }
+    }
+
+    //NIP-25 reaction to a message of the conversation `conv_id`, a contact
+    //PK or a group id. It is wrapped whatever the contact's encryption
+    //scheme, a public reaction would reveal the conversation
+    pub async fn react(
+        &mut self,
+        conv_id: &str,
+        message_id: &str,
+        reaction: &str,
+    ) -> Result<(), Box<dyn std::error::Error + '_>> {
+        let user_pk = self.user.lock()?.get_pk();
+        let (author, kind) = match self.conversations.lock()?.get_message(message_id) {
+            Some((id, message)) if id == conv_id => {
+                (message.get_ev().pubkey, message.get_ev().kind)
+            }
+            _ => return Err(Box::new(CoreTaskHandleError::MessageNotFound)),
+        };
+        let group = self.config.lock()?.get_group(conv_id);
+        let participants = match group {
+            Some(group) => group.participants,
+            None => vec![PublicKey::from_str(conv_id)?],
+        };
+        let rumor = UnsignedEvent::new(
+            user_pk,
+            KIND_REACTION,
+            reaction_tags(message_id, &author, kind),
+            reaction,
+        );
+        if parse_reaction(&rumor).is_none() {
+            return Err(Box::new(CoreTaskHandleError::InvalidReaction));
+        }
+        self.send_rumor(&participants, &rumor).await?;
         Ok(())
     }
 
@@ -154,24 +1124,214 @@ impl CoreTaskHandle {
         return self.relay_pool.get_notifications_ch();
     }
 
-    pub fn add_relay(&mut self, url: String) -> CoreTaskHandleEvent {
-        self.relay_pool.add(&url);
-        CoreTaskHandleEvent::RelayAdded(
-            self.config
-                .add_relay(url)
-                .map_err(|_| CoreTaskHandleError::AddRelayFailed),
-        )
+    //The relay is saved first, then connected and subscribed right away
+    pub async fn add_relay(&mut self, url: String) -> CoreTaskHandleEvent {
+        let url = match normalize_relay_url(&url) {
+            Ok(url) => url,
+            Err(e) => {
+                error!("Failed to add relay: {}", e);
+                return CoreTaskHandleEvent::RelayAdded(Err(CoreTaskHandleError::InvalidRelayUrl));
+            }
+        };
+        let relay = {
+            let mut config = self.config.lock().unwrap();
+            if let Err(e) = config.add_relay(url.clone()) {
+                error!("Failed to add relay {}: {}", url, e);
+                return CoreTaskHandleEvent::RelayAdded(Err(CoreTaskHandleError::AddRelayFailed));
+            }
+            config.get_relay(&url)
+        };
+        if let Some(relay) = relay {
+            if !self.relay_pool.contains(&url) {
+                self.relay_pool.add(&relay);
+            }
+            //Cached limits apply right away, fetched ones once they arrive
+            let limitation = self
+                .relay_infos
+                .lock()
+                .unwrap()
+                .get(&url)
+                .and_then(|i| i.limitation);
+            self.relay_pool.set_limitation(&url, limitation);
+            self.refresh_relay_info(&url);
+            self.relay_pool.connect_relay(&url).await;
+        }
+        CoreTaskHandleEvent::RelayAdded(Ok(()))
     }
 
-    pub fn remove_relay(&mut self, url: String) -> CoreTaskHandleEvent {
-        CoreTaskHandleEvent::RemovedRelay(
-            self.config
-                .remove_relay(&url)
-                .map_err(|_| CoreTaskHandleError::RemoveRelayFailed),
-        )
+    pub async fn update_relay(
+        &mut self,
+        mut relay: RelayConfig,
+    ) -> Result<(), CoreTaskHandleError> {
+        relay.url =
+            normalize_relay_url(&relay.url).map_err(|_| CoreTaskHandleError::InvalidRelayUrl)?;
+        relay.proxy = normalize_optional_proxy(relay.proxy)?;
+        self.config
+            .lock()
+            .unwrap()
+            .update_relay(relay.clone())
+            .map_err(|_| CoreTaskHandleError::UpdateRelayFailed)?;
+        self.relay_pool.update_roles(&relay).await;
+        self.relay_pool.update_proxy(&relay).await;
+        self.relay_pool
+            .set_relay_pow_difficulty(&relay.url, relay.pow_difficulty);
+        Ok(())
+    }
+
+    pub fn get_pow_difficulty(&self) -> Option<u8> {
+        self.config.lock().unwrap().get_pow_difficulty()
+    }
+
+    pub fn set_pow_difficulty(
+        &mut self,
+        difficulty: Option<u8>,
+    ) -> Result<(), CoreTaskHandleError> {
+        if difficulty.map_or(false, |d| d > MAX_POW_DIFFICULTY) {
+            return Err(CoreTaskHandleError::InvalidPowDifficulty);
+        }
+        self.config
+            .lock()
+            .unwrap()
+            .set_pow_difficulty(difficulty)
+            .map_err(|_| CoreTaskHandleError::UpdatePowDifficultyFailed)?;
+        self.relay_pool.set_pow_difficulty(difficulty);
+        Ok(())
+    }
+
+    pub fn get_proxy(&self) -> Option<String> {
+        self.config.lock().unwrap().get_proxy()
     }
-    pub async fn connect_all_relays(&mut self) {
-        self.relay_pool.connect_all().await;
+
+    //Relays without a proxy of their own reconnect through the new one
+    pub async fn set_proxy(&mut self, proxy: Option<String>) -> Result<(), CoreTaskHandleError> {
+        let proxy = normalize_optional_proxy(proxy)?;
+        self.config
+            .lock()
+            .unwrap()
+            .set_proxy(proxy.clone())
+            .map_err(|_| CoreTaskHandleError::UpdateProxyFailed)?;
+        self.http_client.set_proxy(proxy.as_deref());
+        self.relay_pool.set_proxy(proxy).await;
+        Ok(())
+    }
+
+    pub fn list_relays(&self) -> Vec<RelayConfig> {
+        self.config.lock().unwrap().list_relays()
+    }
+
+    //The relay is only dropped from the pool once removed from config
+    pub async fn remove_relay(&mut self, url: String) -> CoreTaskHandleEvent {
+        let url = normalize_relay_url(&url).unwrap_or(url);
+        let res = self.config.lock().unwrap().remove_relay(&url);
+        if let Err(e) = res {
+            error!("Failed to remove relay {}: {}", url, e);
+            return CoreTaskHandleEvent::RemovedRelay(Err(CoreTaskHandleError::RemoveRelayFailed));
+        }
+        self.relay_pool.remove(&url).await;
+        CoreTaskHandleEvent::RemovedRelay(Ok(()))
+    }
+
+    pub async fn get_relay_info(&mut self, url: String) -> Result<RelayInformation, Nip11Error> {
+        let url = normalize_relay_url(&url).map_err(|_e| Nip11Error::InvalidUrl(url))?;
+        self.update_relay_info(&url).await
+    }
+
+    //Cached information is returned while fresh, or if the relay can't be
+    //reached. The pool gets the relay's limitations
+    async fn update_relay_info(&mut self, url: &str) -> Result<RelayInformation, Nip11Error> {
+        let (cached, stale) = {
+            let relay_infos = self.relay_infos.lock().unwrap();
+            (relay_infos.get(url), relay_infos.is_stale(url))
+        };
+        let info = match (cached, stale) {
+            (Some(info), false) => info,
+            (cached, _) if self.relay_pool.proxy_for(url).is_some() => {
+                cached.ok_or(Nip11Error::Proxied)?
+            }
+            (cached, _) => match fetch_relay_info(self.http_client.as_ref(), url).await {
+                Ok(info) => {
+                    if let Err(e) = self.relay_infos.lock().unwrap().insert(url, info.clone()) {
+                        error!("Failed to save relay information of {}: {}", url, e);
+                    }
+                    info
+                }
+                Err(e) => cached.ok_or(e)?,
+            },
+        };
+        self.relay_pool.set_limitation(url, info.limitation.clone());
+        Ok(info)
+    }
+
+    //Limitations fetched in the background since the relays were added
+    fn sync_relay_limitations(&mut self) {
+        let relay_infos = self.relay_infos.lock().unwrap();
+        for relay in self.relay_pool.list_relays() {
+            let limitation = relay_infos.get(&relay.url).and_then(|i| i.limitation);
+            self.relay_pool.set_limitation(&relay.url, limitation);
+        }
+    }
+
+    //Fetched in the background, nothing waits for it. The broker applies
+    //it through `apply_relay_info`
+    fn refresh_relay_info(&self, url: &str) {
+        if self.relay_pool.proxy_for(url).is_some()
+            || !self.relay_infos.lock().unwrap().is_stale(url)
+        {
+            return;
+        }
+        let client = self.http_client.clone();
+        let relay_infos = self.relay_infos.clone();
+        let sender = self.relay_info_sender.clone();
+        let url = url.to_string();
+        tokio::spawn(async move {
+            match fetch_relay_info(client.as_ref(), &url).await {
+                Ok(info) => {
+                    if let Err(e) = relay_infos.lock().unwrap().insert(&url, info) {
+                        error!("Failed to save relay information of {}: {}", url, e);
+                    }
+                    if let Err(e) = sender.send(url).await {
+                        error!("relay_info_sender error: {}", e);
+                    }
+                }
+                Err(e) => debug!("Can't fetch relay information of {}: {}", url, e),
+            }
+        });
+    }
+
+    pub fn take_relay_info_ch(&mut self) -> Option<mpsc::Receiver<String>> {
+        self.relay_info_receiver.take()
+    }
+
+    //Subscriptions of connected relays are updated to the new limits
+    pub async fn apply_relay_info(&mut self, url: &str) {
+        let limitation = self
+            .relay_infos
+            .lock()
+            .unwrap()
+            .get(url)
+            .and_then(|i| i.limitation);
+        self.relay_pool.update_limitation(url, limitation).await;
+    }
+
+    pub async fn event_cache_metrics(&self) -> Option<EventCacheMetrics> {
+        self.relay_pool.event_cache_metrics().await
+    }
+
+    pub fn connect_all_relays(&mut self) {
+        self.sync_relay_limitations();
+        self.relay_pool.connect_all();
+    }
+    pub fn take_connection_ch(&mut self) -> Option<mpsc::Receiver<RelayConnection>> {
+        self.relay_pool.take_connection_ch()
+    }
+    pub async fn handle_relay_connection(&mut self, connection: RelayConnection) {
+        self.relay_pool.handle_connection(connection).await;
+    }
+    pub fn take_disconnection_ch(&mut self) -> Option<mpsc::Receiver<String>> {
+        self.relay_pool.take_disconnection_ch()
+    }
+    pub fn handle_relay_disconnection(&mut self, url: &str) {
+        self.relay_pool.handle_disconnection(url);
     }
     pub async fn connect_relay(&mut self, url: String) {
         self.relay_pool.connect_relay(&url).await;
@@ -181,39 +1341,76 @@ impl CoreTaskHandle {
     }
 
     pub async fn subscribe(&mut self) {
+        self.sync_relay_limitations();
         let authors: Vec<PublicKey> = self
             .config
+            .lock()
+            .unwrap()
             .list_contacts()
             .into_iter()
             .map(|c| c.pk.to_owned())
             .collect();
         let user_pk = self.user.lock().unwrap().get_pk();
-        //Subscribe to DM events whose authors are in
-        //contact list and is intended to user PK
-        let filter_contacts_dm_events = nostr::SubscriptionFilter::new()
-            .authors(authors.clone())
-            .kind(nostr::Kind::EncryptedDirectMessage)
-            .pubkey(user_pk.clone());
-
-        let mut filters = vec![filter_contacts_dm_events];
-        //Subscribe to DM events whose author is the user
-        //and is intended to its contacts
-        //
-        //TODO: Change this to use pubkey as a vector. Needs update
-        // nostr-rs
-        for a in authors.iter() {
+        let mut filters = vec![];
+        if !authors.is_empty() {
+            //Subscribe to DM events, and their deletions, whose authors
+            //are in contact list and is intended to user PK
+            filters.push(
+                Filter::new()
+                    .authors(&authors)
+                    .kinds(&[KIND_ENCRYPTED_DM, KIND_DELETION])
+                    .pubkeys(&[user_pk]),
+            );
+            //Subscribe to DM events, and their deletions, whose author
+            //is the user and is intended to its contacts
             filters.push(
-                nostr::SubscriptionFilter::new()
-                    .authors(vec![user_pk.clone()])
-                    .kind(nostr::Kind::EncryptedDirectMessage)
-                    .pubkey(a.clone()),
+                Filter::new()
+                    .authors(&[user_pk])
+                    .kinds(&[KIND_ENCRYPTED_DM, KIND_DELETION])
+                    .pubkeys(&authors),
             );
         }
 
+        //Subscribe to NIP-17 gift wraps addressed to the user, senders are
+        //hidden inside so they can't be filtered by author
+        filters.push(
+            Filter::new()
+                .kinds(&[nip17::KIND_GIFT_WRAP])
+                .pubkeys(&[user_pk]),
+        );
+
+        //Subscribe to contacts relay lists to send them messages where
+        //they read them, and to ours to restore the relay config
+        let mut contacts_and_user = authors.clone();
+        contacts_and_user.push(user_pk);
+        filters.push(
+            Filter::new()
+                .authors(&contacts_and_user)
+                .kinds(&[KIND_RELAY_LIST, KIND_DM_RELAY_LIST]),
+        );
+
+        //Subscribe to our own contact list to merge changes made by
+        //other clients
+        filters.push(
+            Filter::new()
+                .authors(&[user_pk])
+                .kinds(&[KIND_CONTACT_LIST]),
+        );
+
+        //Subscribe to contacts metadata to show their profiles, and to
+        //our own so edits start from the latest published version
+        filters.push(
+            Filter::new()
+                .authors(&contacts_and_user)
+                .kinds(&[KIND_METADATA]),
+        );
+
         self.relay_pool.start_sub(filters).await;
     }
     pub fn add_contact(&mut self, contact: Contact) -> Result<(), CoreTaskHandleError> {
         self.config
+            .lock()
+            .unwrap()
             .add_contact(contact.clone())
             .map_err(|_| CoreTaskHandleError::AddContactFailed)?;
         self.conversations
@@ -223,24 +1420,78 @@ impl CoreTaskHandle {
         Ok(())
     }
 
+    pub async fn add_contact_by_nip05(
+        &mut self,
+        alias: &str,
+        identifier: &str,
+    ) -> Result<Contact, Box<dyn std::error::Error + '_>> {
+        let identifier = Nip05Identifier::from_str(identifier)?;
+        let record = self.nip05_resolver.resolve(&identifier).await?;
+        if self
+            .config
+            .lock()
+            .unwrap()
+            .get_contact(&record.pk.to_string())
+            .is_some()
+        {
+            return Err(Box::new(CoreTaskHandleError::ContactAlreadyExists));
+        }
+
+        let mut contact = Contact::new(alias, record.pk);
+        contact.nip05 = Some(Nip05Verification::new(
+            &identifier.to_string(),
+            Nip05Status::Verified,
+        ));
+        self.add_contact(contact.clone())?;
+        self.publish_contact_list().await;
+        Ok(contact)
+    }
+
+    pub fn set_contact_encryption(
+        &mut self,
+        pk: &str,
+        encryption: EncryptionScheme,
+    ) -> Result<(), CoreTaskHandleError> {
+        let contact = self
+            .config
+            .lock()
+            .unwrap()
+            .set_contact_encryption(pk, encryption)
+            .map_err(|_| CoreTaskHandleError::UpdateContactFailed)?;
+        if let Some(conv) = self.conversations.lock().unwrap().get_mut_conv(pk) {
+            conv.contact = contact;
+        }
+        Ok(())
+    }
+
     pub async fn remove_contact(&mut self, contact: Contact) {
-        self.config.remove_contact(contact.clone());
-        self.conversations
+        self.config.lock().unwrap().remove_contact(contact.clone());
+        if let Err(e) = self
+            .conversations
             .lock()
             .unwrap()
-            .remove_conv(&contact.pk.to_string());
+            .remove_conv(&contact.pk.to_string())
+        {
+            error!("Failed to remove conversation: {}", e);
+        }
 
         self.relay_pool.remove_contact_events(contact).await;
         //Update filters and resubscribe based on updated conversations
         self.subscribe().await;
+        self.publish_contact_list().await;
     }
 
     pub fn get_config(&self) -> (Vec<String>, Vec<Contact>) {
-        (self.config.list_relays_url(), self.config.list_contacts())
+        let config = self.config.lock().unwrap();
+        (config.list_relays_url(), config.list_contacts())
     }
 
     pub fn import_user_sk(&self, sk: String) {
         self.user.lock().unwrap().import_sk(&sk);
+        //Relay lists of the imported key are restored whatever their age
+        if let Err(e) = self.config.lock().unwrap().set_relay_lists_published_at(0) {
+            error!("Failed to reset relay lists timestamp: {}", e);
+        }
     }
 
     pub fn gen_new_user_keypair(&mut self) {
@@ -0,0 +1,150 @@
+use nostr::{Event, Keys};
+use secp256k1::{
+    schnorrsig::{KeyPair, PublicKey},
+    Message, Secp256k1,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+//Helpers to build and inspect events through their NIP-01 wire format,
+//so we are not limited to the kinds and tags nostr-rs knows about
+
+#[derive(Debug, Error)]
+pub enum EventError {
+    #[error("Missing secret key")]
+    MissingSecretKey,
+    #[error("Invalid event: `{0}`")]
+    InvalidEvent(String),
+}
+
+pub const KIND_ENCRYPTED_DM: u64 = 4;
+
+//Event without signature, used for the content we show in conversations.
+//NIP-59 rumors are never signed so they can't be a nostr-rs `Event`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UnsignedEvent {
+    pub id: String,
+    pub pubkey: PublicKey,
+    pub created_at: i64,
+    pub kind: u64,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+}
+
+impl UnsignedEvent {
+    pub fn new(pubkey: PublicKey, kind: u64, tags: Vec<Vec<String>>, content: &str) -> Self {
+        let created_at = chrono::offset::Utc::now().timestamp();
+        Self {
+            id: hex(&gen_id(&pubkey, created_at, kind, &tags, content)),
+            pubkey,
+            created_at,
+            kind,
+            tags,
+            content: content.into(),
+        }
+    }
+
+    pub fn from_event(ev: &Event) -> Result<Self, EventError> {
+        serde_json::to_value(ev)
+            .and_then(serde_json::from_value)
+            .map_err(|e| EventError::InvalidEvent(e.to_string()))
+    }
+
+    pub fn verify_id(&self) -> bool {
+        self.id
+            == hex(&gen_id(
+                &self.pubkey,
+                self.created_at,
+                self.kind,
+                &self.tags,
+                &self.content,
+            ))
+    }
+
+    pub fn get_tag_values(&self, name: &str) -> Vec<String> {
+        self.tags
+            .iter()
+            .filter(|t| t.len() >= 2 && t[0] == name)
+            .map(|t| t[1].clone())
+            .collect()
+    }
+}
+
+pub fn new_signed_event(
+    keys: &Keys,
+    kind: u64,
+    tags: Vec<Vec<String>>,
+    content: &str,
+) -> Result<Event, EventError> {
+    let created_at = chrono::offset::Utc::now().timestamp();
+    new_signed_event_at(keys, created_at, kind, tags, content)
+}
+
+pub fn new_signed_event_at(
+    keys: &Keys,
+    created_at: i64,
+    kind: u64,
+    tags: Vec<Vec<String>>,
+    content: &str,
+) -> Result<Event, EventError> {
+    let sk = keys
+        .secret_key()
+        .map_err(|_e| EventError::MissingSecretKey)?;
+    let id = gen_id(&keys.public_key, created_at, kind, &tags, content);
+
+    let secp = Secp256k1::new();
+    let key_pair = KeyPair::from_secret_key(&secp, sk);
+    let msg = Message::from_slice(&id).map_err(|e| EventError::InvalidEvent(e.to_string()))?;
+    let sig = secp.schnorrsig_sign(&msg, &key_pair);
+
+    serde_json::from_value(json!({
+        "id": hex(&id),
+        "pubkey": keys.public_key.to_string(),
+        "created_at": created_at,
+        "kind": kind,
+        "tags": tags,
+        "content": content,
+        "sig": sig.to_string(),
+    }))
+    .map_err(|e| EventError::InvalidEvent(e.to_string()))
+}
+
+pub fn get_kind(ev: &Event) -> u64 {
+    serde_json::to_value(&ev.kind)
+        .ok()
+        .and_then(|v| v.as_u64())
+        .unwrap_or_default()
+}
+
+pub fn get_tags(ev: &Event) -> Vec<Vec<String>> {
+    serde_json::to_value(&ev.tags)
+        .ok()
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+//Returns the value of every tag named `name`
+pub fn get_tag_values(ev: &Event, name: &str) -> Vec<String> {
+    get_tags(ev)
+        .into_iter()
+        .filter(|t| t.len() >= 2 && t[0] == name)
+        .map(|t| t[1].clone())
+        .collect()
+}
+
+pub fn gen_id(
+    pubkey: &PublicKey,
+    created_at: i64,
+    kind: u64,
+    tags: &[Vec<String>],
+    content: &str,
+) -> [u8; 32] {
+    let serialized = json!([0, pubkey.to_string(), created_at, kind, tags, content]).to_string();
+    Sha256::digest(serialized.as_bytes()).into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
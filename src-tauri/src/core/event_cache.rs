@@ -0,0 +1,230 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use nostr::Event;
+use serde::Serialize;
+
+use super::conversations::Conversations;
+
+pub const DEFAULT_EVENT_CACHE_SIZE: usize = 10_000;
+
+//Where events older than the cache can still be found
+pub trait EventStore: Send + Sync {
+    fn contains_event(&self, id: &str) -> bool;
+}
+
+//Messages added to conversations are never received twice. Not bounded:
+//the ids of a conversation are kept as long as its messages, and forgotten
+//with them when the conversation is removed
+impl EventStore for Mutex<Conversations> {
+    fn contains_event(&self, id: &str) -> bool {
+        self.lock().map(|c| c.contains_event(id)).unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EventCacheMetrics {
+    pub size: usize,
+    pub capacity: usize,
+    //Duplicates found in the cache
+    pub hits: u64,
+    //Duplicates found in the store after being evicted
+    pub store_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub hit_rate: f64,
+}
+
+//Author and first `p` tag, to forget a contact's events
+struct CachedEvent {
+    last_seen: u64,
+    pubkey: String,
+    p: Option<String>,
+}
+
+//Ids of the events received or sent lately, to propagate each event once.
+//The least recently seen ones are evicted past the capacity
+pub struct EventCache {
+    capacity: usize,
+    entries: HashMap<String, CachedEvent>,
+    order: BTreeMap<u64, String>,
+    seq: u64,
+    store: Option<Arc<dyn EventStore>>,
+    metrics: EventCacheMetrics,
+}
+
+impl EventCache {
+    pub fn new(capacity: usize, store: Option<Arc<dyn EventStore>>) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            seq: 0,
+            store,
+            metrics: EventCacheMetrics::default(),
+        }
+    }
+
+    //Returns true if the event wasn't seen before
+    pub fn insert(&mut self, ev: &Event) -> bool {
+        let id = ev.id.to_string();
+        if self.touch(&id) {
+            self.metrics.hits += 1;
+            return false;
+        }
+        let stored = self
+            .store
+            .as_ref()
+            .map_or(false, |store| store.contains_event(&id));
+        if stored {
+            self.metrics.store_hits += 1;
+        } else {
+            self.metrics.misses += 1;
+        }
+        self.add(id, ev);
+        !stored
+    }
+
+    //Events we send are not propagated when relays echo them
+    pub fn mark_sent(&mut self, ev: &Event) {
+        let id = ev.id.to_string();
+        if !self.touch(&id) {
+            self.add(id, ev);
+        }
+    }
+
+    //Events from or to `pk` are propagated again if received
+    pub fn remove_related(&mut self, pk: &str) {
+        let order = &mut self.order;
+        self.entries.retain(|_, e| {
+            let related = e.pubkey == pk || e.p.as_deref() == Some(pk);
+            if related {
+                order.remove(&e.last_seen);
+            }
+            !related
+        });
+    }
+
+    pub fn metrics(&self) -> EventCacheMetrics {
+        let duplicates = self.metrics.hits + self.metrics.store_hits;
+        let total = duplicates + self.metrics.misses;
+        EventCacheMetrics {
+            size: self.entries.len(),
+            capacity: self.capacity,
+            hit_rate: if total == 0 {
+                0.0
+            } else {
+                duplicates as f64 / total as f64
+            },
+            ..self.metrics.clone()
+        }
+    }
+
+    fn touch(&mut self, id: &str) -> bool {
+        match self.entries.get_mut(id) {
+            Some(entry) => {
+                self.order.remove(&entry.last_seen);
+                self.seq += 1;
+                entry.last_seen = self.seq;
+                self.order.insert(self.seq, id.into());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add(&mut self, id: String, ev: &Event) {
+        self.seq += 1;
+        self.entries.insert(
+            id.clone(),
+            CachedEvent {
+                last_seen: self.seq,
+                pubkey: ev.pubkey.to_string(),
+                p: ev.tags.first().map(|t| t.content().to_string()),
+            },
+        );
+        self.order.insert(self.seq, id);
+        while self.entries.len() > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(seq) => *seq,
+                None => break,
+            };
+            if let Some(id) = self.order.remove(&oldest) {
+                self.entries.remove(&id);
+                self.metrics.evictions += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::new_signed_event_at;
+    use nostr::Keys;
+    use std::collections::HashSet;
+
+    struct FakeStore(HashSet<String>);
+
+    impl EventStore for FakeStore {
+        fn contains_event(&self, id: &str) -> bool {
+            self.0.contains(id)
+        }
+    }
+
+    fn events(n: usize) -> Vec<Event> {
+        let keys = Keys::generate_from_os_random().unwrap();
+        (0..n)
+            .map(|i| new_signed_event_at(&keys, 1_700_000_000, 1, vec![], &i.to_string()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_seen() {
+        let evs = events(3);
+        let mut cache = EventCache::new(2, None);
+        assert!(cache.insert(&evs[0]));
+        assert!(cache.insert(&evs[1]));
+        //Seeing the first one again makes the second the oldest
+        assert!(!cache.insert(&evs[0]));
+        assert!(cache.insert(&evs[2]));
+
+        assert!(!cache.insert(&evs[0]));
+        assert!(!cache.insert(&evs[2]));
+        assert!(cache.insert(&evs[1]));
+
+        let metrics = cache.metrics();
+        assert_eq!((metrics.size, metrics.capacity), (2, 2));
+        assert_eq!(metrics.evictions, 2);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let evs = events(3);
+        let store = FakeStore(HashSet::from([evs[2].id.to_string()]));
+        let mut cache = EventCache::new(10, Some(Arc::new(store)));
+        assert!(cache.insert(&evs[0]));
+        assert!(!cache.insert(&evs[0]));
+        assert!(cache.insert(&evs[1]));
+        //Evicted events are still found in the store
+        assert!(!cache.insert(&evs[2]));
+        //And cached from then on
+        assert!(!cache.insert(&evs[2]));
+
+        let metrics = cache.metrics();
+        assert_eq!(metrics.hits, 2);
+        assert_eq!(metrics.store_hits, 1);
+        assert_eq!(metrics.misses, 2);
+        assert_eq!(metrics.hit_rate, 0.6);
+    }
+
+    #[test]
+    fn sent_events_are_not_propagated() {
+        let evs = events(1);
+        let mut cache = EventCache::new(10, None);
+        cache.mark_sent(&evs[0]);
+        assert!(!cache.insert(&evs[0]));
+    }
+}
@@ -0,0 +1,74 @@
+use async_trait::async_trait;
+use std::{sync::RwLock, time::Duration};
+use thiserror::Error;
+use url::Url;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+#[error("HTTP request failed: `{0}`")]
+pub struct HttpError(pub String);
+
+//HTTP is only used to fetch small JSON documents (NIP-05, NIP-11). The trait
+//lets them be fetched from a local stand-in instead of the network
+#[async_trait]
+pub trait HttpClient: Send + Sync {
+    async fn get(&self, url: &Url, accept: &str) -> Result<String, HttpError>;
+
+    //Later requests go through the SOCKS5 proxy `host:port`
+    fn set_proxy(&self, _proxy: Option<&str>) {}
+}
+
+//`None` when the client couldn't be built, requests fail rather than
+//bypassing the proxy
+pub struct ReqwestClient {
+    client: RwLock<Option<reqwest::Client>>,
+}
+
+impl ReqwestClient {
+    pub fn new(proxy: Option<&str>) -> Self {
+        Self {
+            client: RwLock::new(build_client(proxy)),
+        }
+    }
+}
+
+//Host names are resolved by the proxy (socks5h), like for relays
+fn build_client(proxy: Option<&str>) -> Option<reqwest::Client> {
+    //Redirects are ignored, NIP-05 requires the domain to answer by itself
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(REQUEST_TIMEOUT);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(format!("socks5h://{}", proxy)).ok()?);
+    }
+    builder.build().ok()
+}
+
+#[async_trait]
+impl HttpClient for ReqwestClient {
+    async fn get(&self, url: &Url, accept: &str) -> Result<String, HttpError> {
+        let client = self
+            .client
+            .read()
+            .map_err(|e| HttpError(e.to_string()))?
+            .clone()
+            .ok_or_else(|| HttpError("HTTP client unavailable".into()))?;
+        let res = client
+            .get(url.clone())
+            .header(reqwest::header::ACCEPT, accept)
+            .send()
+            .await
+            .map_err(|e| HttpError(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(HttpError(res.status().to_string()));
+        }
+        res.text().await.map_err(|e| HttpError(e.to_string()))
+    }
+
+    fn set_proxy(&self, proxy: Option<&str>) {
+        if let Ok(mut client) = self.client.write() {
+            *client = build_client(proxy);
+        }
+    }
+}
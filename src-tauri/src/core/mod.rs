@@ -1,6 +1,22 @@
 pub mod config;
 pub mod conversations;
 pub mod core;
+pub mod event;
+pub mod event_cache;
+pub mod http;
+pub mod nip02;
+pub mod nip05;
+pub mod nip09;
+pub mod nip10;
+pub mod nip11;
+pub mod nip13;
+pub mod nip17;
+pub mod nip25;
+pub mod nip42;
+pub mod nip44;
+pub mod nip65;
+pub mod profiles;
 pub mod relay_pool;
+pub mod socks;
 pub mod subscription;
 pub mod user;
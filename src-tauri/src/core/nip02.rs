@@ -0,0 +1,333 @@
+use log::{info, warn};
+use secp256k1::schnorrsig::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, Error},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use super::{
+    config::{ConfigProvider, Contact},
+    event::UnsignedEvent,
+};
+
+//NIP-02 contact list
+//https://github.com/nostr-protocol/nips/blob/master/02.md
+
+pub const KIND_CONTACT_LIST: u64 = 3;
+const CONTACT_LIST_FILENAME: &str = "contact_list.json";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContactListEntry {
+    pub pk: PublicKey,
+    pub relay_hint: Option<String>,
+    pub petname: Option<String>,
+}
+
+impl ContactListEntry {
+    //["p", <pk>, <relay url>, <petname>], the last two are optional
+    fn from_tag(tag: &[String]) -> Option<Self> {
+        if tag.len() < 2 || tag[0] != "p" {
+            return None;
+        }
+        let pk = PublicKey::from_str(&tag[1]).ok()?;
+        let non_empty = |i: usize| tag.get(i).filter(|v| !v.is_empty()).cloned();
+        Some(Self {
+            pk,
+            relay_hint: non_empty(2),
+            petname: non_empty(3),
+        })
+    }
+
+    fn from_contact(contact: &Contact) -> Self {
+        Self {
+            pk: contact.pk,
+            relay_hint: contact.relay_hint.clone(),
+            petname: Some(contact.alias.clone()),
+        }
+    }
+
+    fn to_tag(&self) -> Vec<String> {
+        vec![
+            "p".into(),
+            self.pk.to_string(),
+            self.relay_hint.clone().unwrap_or_default(),
+            self.petname.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+//Changes to apply locally after merging a contact list from relays
+#[derive(Debug, Default)]
+pub struct ContactListMerge {
+    pub added: Vec<ContactListEntry>,
+    pub updated: Vec<Contact>,
+    pub removed: Vec<Contact>,
+    //Local changes the received list doesn't have yet
+    pub needs_publish: bool,
+}
+
+//The last contact list merged or published is kept as the common base, so
+//changes made by other clients can be told apart from local ones
+#[derive(Default, Serialize, Deserialize)]
+pub struct ContactListSync {
+    #[serde(default)]
+    pub publish: bool,
+    #[serde(default)]
+    last: Option<UnsignedEvent>,
+}
+
+impl ContactListSync {
+    pub fn load() -> Self {
+        let path = Self::get_contact_list_path();
+        info!("Loading contact list sync from file {}", path.display());
+        match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+                warn!("Can't parse contact list file: {}", e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn set_publish(&mut self, publish: bool) -> Result<(), Error> {
+        self.publish = publish;
+        self.save()
+    }
+
+    //Three-way merge of the received list, the last synced one and the
+    //local contacts. Returns None if the list is not newer than the base,
+    //otherwise the list becomes the base
+    pub fn merge(&mut self, ev: &UnsignedEvent, local: &[Contact]) -> Option<ContactListMerge> {
+        let merge = self.diff(ev, local)?;
+        self.last = Some(ev.clone());
+        if let Err(e) = self.save() {
+            warn!("Can't save contact list: {}", e);
+        }
+        Some(merge)
+    }
+
+    fn diff(&self, ev: &UnsignedEvent, local: &[Contact]) -> Option<ContactListMerge> {
+        if let Some(last) = &self.last {
+            if ev.created_at <= last.created_at {
+                return None;
+            }
+        }
+        let base = self.base_pks();
+        let remote: Vec<ContactListEntry> = ev
+            .tags
+            .iter()
+            .filter_map(|t| ContactListEntry::from_tag(t))
+            .collect();
+        let remote_pks: HashSet<String> = remote.iter().map(|e| e.pk.to_string()).collect();
+        let local: HashMap<String, &Contact> =
+            local.iter().map(|c| (c.pk.to_string(), c)).collect();
+
+        let mut merge = ContactListMerge::default();
+        for entry in remote {
+            let pk = entry.pk.to_string();
+            match local.get(&pk) {
+                //Removed locally since the last sync, the removal wins
+                None if base.contains(&pk) => (),
+                None => merge.added.push(entry),
+                Some(contact)
+                    if entry.relay_hint.is_some() && entry.relay_hint != contact.relay_hint =>
+                {
+                    let mut contact = (*contact).clone();
+                    contact.relay_hint = entry.relay_hint;
+                    merge.updated.push(contact);
+                }
+                Some(_) => (),
+            }
+        }
+        for (pk, contact) in local.iter() {
+            if base.contains(pk) && !remote_pks.contains(pk) {
+                merge.removed.push((*contact).clone());
+            }
+        }
+        merge.needs_publish = local
+            .keys()
+            .any(|pk| !remote_pks.contains(pk) && !base.contains(pk))
+            || base
+                .iter()
+                .any(|pk| !local.contains_key(pk) && remote_pks.contains(pk));
+        Some(merge)
+    }
+
+    //Tags and content of the list to publish. Tags other than `p` and the
+    //content set by other clients are kept
+    pub fn build(&self, local: &[Contact]) -> (Vec<Vec<String>>, String) {
+        let (tags, content) = match &self.last {
+            Some(last) => (last.tags.clone(), last.content.clone()),
+            None => (vec![], String::new()),
+        };
+        let mut pending: HashMap<String, &Contact> =
+            local.iter().map(|c| (c.pk.to_string(), c)).collect();
+
+        let mut tags: Vec<Vec<String>> = tags
+            .into_iter()
+            .filter_map(|t| match ContactListEntry::from_tag(&t) {
+                Some(entry) => pending
+                    .remove(&entry.pk.to_string())
+                    .map(|c| ContactListEntry::from_contact(c).to_tag()),
+                None => Some(t),
+            })
+            .collect();
+
+        let mut new_contacts: Vec<&Contact> = pending.into_values().collect();
+        new_contacts.sort_by_key(|c| c.pk.to_string());
+        tags.extend(
+            new_contacts
+                .into_iter()
+                .map(|c| ContactListEntry::from_contact(c).to_tag()),
+        );
+        (tags, content)
+    }
+
+    pub fn set_published(&mut self, ev: UnsignedEvent) -> Result<(), Error> {
+        self.last = Some(ev);
+        self.save()
+    }
+
+    fn base_pks(&self) -> HashSet<String> {
+        match &self.last {
+            Some(last) => last
+                .tags
+                .iter()
+                .filter_map(|t| ContactListEntry::from_tag(t))
+                .map(|e| e.pk.to_string())
+                .collect(),
+            None => HashSet::new(),
+        }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let serialized = serde_json::to_string_pretty(self)?;
+        std::fs::write(Self::get_contact_list_path(), serialized)
+    }
+
+    fn get_contact_list_path() -> PathBuf {
+        let mut path = ConfigProvider::get_path();
+        path.push(CONTACT_LIST_FILENAME);
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    const RELAY: &str = "wss://relay.example.com";
+
+    fn new_pk() -> PublicKey {
+        Keys::generate_from_os_random().unwrap().public_key
+    }
+
+    //Contact list of `entries`, with their relay hint
+    fn list(created_at: i64, entries: &[(PublicKey, &str)]) -> UnsignedEvent {
+        UnsignedEvent {
+            id: created_at.to_string(),
+            pubkey: new_pk(),
+            created_at,
+            kind: KIND_CONTACT_LIST,
+            tags: entries
+                .iter()
+                .map(|(pk, hint)| vec!["p".into(), pk.to_string(), hint.to_string()])
+                .collect(),
+            content: String::new(),
+        }
+    }
+
+    fn synced(last: UnsignedEvent) -> ContactListSync {
+        ContactListSync {
+            publish: true,
+            last: Some(last),
+        }
+    }
+
+    fn contacts(pks: &[PublicKey]) -> Vec<Contact> {
+        pks.iter().map(|pk| Contact::new("", *pk)).collect()
+    }
+
+    fn pks(contacts: &[Contact]) -> Vec<PublicKey> {
+        contacts.iter().map(|c| c.pk).collect()
+    }
+
+    #[test]
+    fn local_removal_wins() {
+        let (a, b) = (new_pk(), new_pk());
+        let sync = synced(list(1, &[(a, ""), (b, "")]));
+        let merge = sync
+            .diff(&list(2, &[(a, ""), (b, "")]), &contacts(&[a]))
+            .unwrap();
+        assert!(merge.added.is_empty());
+        assert!(merge.removed.is_empty());
+        assert!(merge.needs_publish);
+    }
+
+    #[test]
+    fn adds_contacts_added_remotely() {
+        let (a, c) = (new_pk(), new_pk());
+        let sync = synced(list(1, &[(a, "")]));
+        let merge = sync
+            .diff(&list(2, &[(a, ""), (c, RELAY)]), &contacts(&[a]))
+            .unwrap();
+        assert_eq!(
+            merge.added,
+            vec![ContactListEntry {
+                pk: c,
+                relay_hint: Some(RELAY.into()),
+                petname: None,
+            }]
+        );
+        assert!(merge.removed.is_empty());
+        assert!(!merge.needs_publish);
+    }
+
+    #[test]
+    fn removes_contacts_removed_remotely() {
+        let (a, b) = (new_pk(), new_pk());
+        let sync = synced(list(1, &[(a, ""), (b, "")]));
+        let merge = sync.diff(&list(2, &[(a, "")]), &contacts(&[a, b])).unwrap();
+        assert!(merge.added.is_empty());
+        assert_eq!(pks(&merge.removed), vec![b]);
+        assert!(!merge.needs_publish);
+    }
+
+    #[test]
+    fn applies_relay_hint_updates() {
+        let a = new_pk();
+        let sync = synced(list(1, &[(a, "")]));
+        let merge = sync.diff(&list(2, &[(a, RELAY)]), &contacts(&[a])).unwrap();
+        assert_eq!(pks(&merge.updated), vec![a]);
+        assert_eq!(merge.updated[0].relay_hint.as_deref(), Some(RELAY));
+    }
+
+    #[test]
+    fn ignores_lists_not_newer() {
+        let a = new_pk();
+        let sync = synced(list(2, &[(a, "")]));
+        assert!(sync.diff(&list(2, &[]), &contacts(&[a])).is_none());
+        assert!(sync.diff(&list(1, &[]), &contacts(&[a])).is_none());
+    }
+
+    #[test]
+    fn first_import_keeps_local_contacts() {
+        let (a, c, l) = (new_pk(), new_pk(), new_pk());
+        let sync = ContactListSync::default();
+        let merge = sync
+            .diff(&list(1, &[(a, ""), (c, "")]), &contacts(&[a, l]))
+            .unwrap();
+        assert_eq!(
+            merge.added.iter().map(|e| e.pk).collect::<Vec<_>>(),
+            vec![c]
+        );
+        assert!(merge.removed.is_empty());
+        //The local only contact goes to the published list
+        assert!(merge.needs_publish);
+    }
+}
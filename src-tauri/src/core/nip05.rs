@@ -0,0 +1,280 @@
+use secp256k1::schnorrsig::PublicKey;
+use serde::Deserialize;
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+use thiserror::Error;
+use url::{Host, Url};
+
+use super::http::{HttpClient, HttpError, ReqwestClient};
+
+//NIP-05 mapping of `name@domain` identifiers to public keys
+//https://github.com/nostr-protocol/nips/blob/master/05.md
+
+#[derive(Debug, Error)]
+pub enum Nip05Error {
+    #[error("Invalid identifier: `{0}`")]
+    InvalidIdentifier(String),
+    #[error(transparent)]
+    RequestFailed(#[from] HttpError),
+    #[error("Invalid nostr.json: `{0}`")]
+    InvalidResponse(String),
+    #[error("`{0}` not found")]
+    NameNotFound(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nip05Identifier {
+    pub name: String,
+    pub domain: String,
+}
+
+impl Nip05Identifier {
+    pub fn well_known_url(&self) -> Result<Url, Nip05Error> {
+        let invalid = || Nip05Error::InvalidIdentifier(self.to_string());
+        let mut url = Url::parse(&format!("https://{}/.well-known/nostr.json", self.domain))
+            .map_err(|_e| invalid())?;
+        //Plain HTTP is only used for local servers
+        let local = match url.host() {
+            Some(Host::Domain(domain)) => domain == "localhost",
+            Some(Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        };
+        if local {
+            url.set_scheme("http").map_err(|_e| invalid())?;
+        }
+        url.query_pairs_mut().append_pair("name", &self.name);
+        Ok(url)
+    }
+}
+
+impl FromStr for Nip05Identifier {
+    type Err = Nip05Error;
+
+    //A bare domain stands for the `_` (root) name of that domain
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (name, domain) = s.split_once('@').unwrap_or(("_", &s));
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !valid_name || domain.is_empty() || domain.contains('/') {
+            return Err(Nip05Error::InvalidIdentifier(s.clone()));
+        }
+        Ok(Self {
+            name: name.into(),
+            domain: domain.into(),
+        })
+    }
+}
+
+impl fmt::Display for Nip05Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.name == "_" {
+            write!(f, "{}", self.domain)
+        } else {
+            write!(f, "{}@{}", self.name, self.domain)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Nip05Record {
+    pub pk: PublicKey,
+    pub relays: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct NostrJson {
+    names: HashMap<String, String>,
+    #[serde(default)]
+    relays: HashMap<String, Vec<String>>,
+}
+
+#[derive(Clone)]
+pub struct Nip05Resolver {
+    client: Arc<dyn HttpClient>,
+}
+
+impl Nip05Resolver {
+    pub fn new(client: Arc<dyn HttpClient>) -> Self {
+        Self { client }
+    }
+
+    pub async fn resolve(&self, identifier: &Nip05Identifier) -> Result<Nip05Record, Nip05Error> {
+        let body = self
+            .client
+            .get(&identifier.well_known_url()?, "application/json")
+            .await?;
+        let nostr_json: NostrJson =
+            serde_json::from_str(&body).map_err(|e| Nip05Error::InvalidResponse(e.to_string()))?;
+
+        let pk_hex = nostr_json
+            .names
+            .get(&identifier.name)
+            .ok_or_else(|| Nip05Error::NameNotFound(identifier.to_string()))?;
+        let pk = PublicKey::from_str(pk_hex)
+            .map_err(|_e| Nip05Error::InvalidResponse(format!("Invalid PK {}", pk_hex)))?;
+        let relays = nostr_json.relays.get(pk_hex).cloned().unwrap_or_default();
+        Ok(Nip05Record { pk, relays })
+    }
+
+    //Ok(false) means the identifier now points to someone else
+    pub async fn verify(
+        &self,
+        identifier: &Nip05Identifier,
+        pk: &PublicKey,
+    ) -> Result<bool, Nip05Error> {
+        match self.resolve(identifier).await {
+            Ok(record) => Ok(&record.pk == pk),
+            Err(Nip05Error::NameNotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Default for Nip05Resolver {
+    fn default() -> Self {
+        Self::new(Arc::new(ReqwestClient::new(None)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    const PK: &str = "b0635d6a9851d3aed0cd6c495b282167acf761729078d975fc341b22650b07b9";
+    const OTHER_PK: &str = "32e1827635450ebb3c5a7d12c1f8e7b2b514439ac10a67eef3d9fd9c5c68e245";
+
+    //Serves a fixed nostr.json and records the requested URLs
+    struct FakeHttpClient {
+        body: Result<String, String>,
+        requested: Mutex<Vec<String>>,
+    }
+
+    impl FakeHttpClient {
+        fn new(body: Result<&str, &str>) -> Arc<Self> {
+            Arc::new(Self {
+                body: body.map(String::from).map_err(String::from),
+                requested: Mutex::new(vec![]),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for FakeHttpClient {
+        async fn get(&self, url: &Url, _accept: &str) -> Result<String, HttpError> {
+            self.requested.lock().unwrap().push(url.to_string());
+            self.body.clone().map_err(HttpError)
+        }
+    }
+
+    fn nostr_json(pk: &str) -> String {
+        format!(
+            r#"{{"names":{{"bob":"{}"}},"relays":{{"{}":["wss://relay.example.com"]}}}}"#,
+            pk, pk
+        )
+    }
+
+    #[tokio::test]
+    async fn resolves_identifier() {
+        let body = nostr_json(PK);
+        let client = FakeHttpClient::new(Ok(&body));
+        let resolver = Nip05Resolver::new(client.clone());
+        let identifier = Nip05Identifier::from_str("Bob@Example.com").unwrap();
+
+        let record = resolver.resolve(&identifier).await.unwrap();
+        assert_eq!(record.pk, PublicKey::from_str(PK).unwrap());
+        assert_eq!(record.relays, vec!["wss://relay.example.com".to_string()]);
+        assert_eq!(
+            *client.requested.lock().unwrap(),
+            vec!["https://example.com/.well-known/nostr.json?name=bob".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn name_missing_from_names() {
+        let body = nostr_json(PK);
+        let resolver = Nip05Resolver::new(FakeHttpClient::new(Ok(&body)));
+        let identifier = Nip05Identifier::from_str("alice@example.com").unwrap();
+
+        assert!(matches!(
+            resolver.resolve(&identifier).await,
+            Err(Nip05Error::NameNotFound(name)) if name == "alice@example.com"
+        ));
+        let pk = PublicKey::from_str(PK).unwrap();
+        assert!(!resolver.verify(&identifier, &pk).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn flags_pk_mismatch_on_verify() {
+        let identifier = Nip05Identifier::from_str("bob@example.com").unwrap();
+        let pk = PublicKey::from_str(PK).unwrap();
+
+        let body = nostr_json(PK);
+        let resolver = Nip05Resolver::new(FakeHttpClient::new(Ok(&body)));
+        assert!(resolver.verify(&identifier, &pk).await.unwrap());
+
+        //The domain now maps the name to someone else
+        let body = nostr_json(OTHER_PK);
+        let resolver = Nip05Resolver::new(FakeHttpClient::new(Ok(&body)));
+        assert!(!resolver.verify(&identifier, &pk).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn request_failures_are_errors() {
+        let identifier = Nip05Identifier::from_str("bob@example.com").unwrap();
+        let pk = PublicKey::from_str(PK).unwrap();
+
+        let resolver = Nip05Resolver::new(FakeHttpClient::new(Err("404 Not Found")));
+        assert!(matches!(
+            resolver.verify(&identifier, &pk).await,
+            Err(Nip05Error::RequestFailed(_))
+        ));
+        let resolver = Nip05Resolver::new(FakeHttpClient::new(Ok("<html></html>")));
+        assert!(matches!(
+            resolver.resolve(&identifier).await,
+            Err(Nip05Error::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn parses_identifiers() {
+        let identifier = Nip05Identifier::from_str("example.com").unwrap();
+        assert_eq!(identifier.name, "_");
+        assert_eq!(identifier.to_string(), "example.com");
+        assert_eq!(
+            identifier.well_known_url().unwrap().as_str(),
+            "https://example.com/.well-known/nostr.json?name=_"
+        );
+        let identifier = Nip05Identifier::from_str("bob@localhost:8080").unwrap();
+        assert_eq!(
+            identifier.well_known_url().unwrap().as_str(),
+            "http://localhost:8080/.well-known/nostr.json?name=bob"
+        );
+        let identifier = Nip05Identifier::from_str("bob@127.0.0.1").unwrap();
+        assert_eq!(
+            identifier.well_known_url().unwrap().as_str(),
+            "http://127.0.0.1/.well-known/nostr.json?name=bob"
+        );
+        //Only exact local hosts are requested over plain HTTP
+        for lookalike in ["bob@localhost.evil.com", "bob@127.attacker.net"] {
+            let identifier = Nip05Identifier::from_str(lookalike).unwrap();
+            assert_eq!(identifier.well_known_url().unwrap().scheme(), "https");
+        }
+        for invalid in [
+            "",
+            "bob@",
+            "@example.com",
+            "b b@example.com",
+            "bob@example.com/x",
+        ] {
+            assert!(matches!(
+                Nip05Identifier::from_str(invalid),
+                Err(Nip05Error::InvalidIdentifier(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use secp256k1::schnorrsig::PublicKey;
+
+//NIP-09 event deletion requests
+//https://github.com/nostr-protocol/nips/blob/master/09.md
+
+pub const KIND_DELETION: u64 = 5;
+
+//`e` tags of the deleted events and the `k` tag of their kind. Peers are
+//tagged so they can subscribe to the deletions addressed to them
+pub fn deletion_tags(ids: &[String], kind: u64, peers: &[PublicKey]) -> Vec<Vec<String>> {
+    let mut tags: Vec<Vec<String>> = peers
+        .iter()
+        .map(|pk| vec!["p".into(), pk.to_string()])
+        .collect();
+    tags.extend(ids.iter().map(|id| vec!["e".into(), id.clone()]));
+    tags.push(vec!["k".into(), kind.to_string()]);
+    tags
+}
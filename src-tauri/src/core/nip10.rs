@@ -0,0 +1,44 @@
+//NIP-10 `e` tag markers, used to reply to messages. Edits use their own
+//`edit` marker to reference the message they replace
+//https://github.com/nostr-protocol/nips/blob/master/10.md
+
+const REPLY_MARKER: &str = "reply";
+const EDIT_MARKER: &str = "edit";
+
+fn marked_tag(message_id: &str, marker: &str) -> Vec<String> {
+    vec!["e".into(), message_id.into(), "".into(), marker.into()]
+}
+
+pub fn reply_tag(message_id: &str) -> Vec<String> {
+    marked_tag(message_id, REPLY_MARKER)
+}
+
+pub fn edit_tag(message_id: &str) -> Vec<String> {
+    marked_tag(message_id, EDIT_MARKER)
+}
+
+//The `e` tag marked as reply, or else the last one without marker as
+//NIP-17 messages reference the message they reply to
+pub fn get_reply_to(tags: &[Vec<String>]) -> Option<String> {
+    let e_tags: Vec<&Vec<String>> = tags
+        .iter()
+        .filter(|t| t.len() >= 2 && t[0] == "e")
+        .collect();
+    e_tags
+        .iter()
+        .find(|t| t.get(3).map(String::as_str) == Some(REPLY_MARKER))
+        .or_else(|| {
+            e_tags
+                .iter()
+                .rev()
+                .find(|t| t.get(3).map_or(true, |marker| marker.is_empty()))
+        })
+        .map(|t| t[1].clone())
+}
+
+//Id of the message an edit replaces
+pub fn get_edit_of(tags: &[Vec<String>]) -> Option<String> {
+    tags.iter()
+        .find(|t| t.len() >= 4 && t[0] == "e" && t[3] == EDIT_MARKER)
+        .map(|t| t[1].clone())
+}
@@ -0,0 +1,187 @@
+use log::{info, warn};
+use nostr::{ClientMessage, Event};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Error},
+    path::PathBuf,
+};
+use thiserror::Error;
+use url::Url;
+
+use super::{
+    config::ConfigProvider,
+    event::get_tags,
+    http::{HttpClient, HttpError},
+};
+
+//NIP-11 relay information document
+//https://github.com/nostr-protocol/nips/blob/master/11.md
+
+const RELAY_INFOS_FILENAME: &str = "relay_infos.json";
+//Documents older than this are fetched again
+const RELAY_INFO_TTL: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Error)]
+pub enum Nip11Error {
+    #[error("Invalid relay URL: `{0}`")]
+    InvalidUrl(String),
+    #[error(transparent)]
+    RequestFailed(#[from] HttpError),
+    #[error("Invalid relay information: `{0}`")]
+    InvalidDocument(String),
+    //HTTP requests don't go through the relay's proxy
+    #[error("Relay information isn't fetched for proxied relays")]
+    Proxied,
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum LimitationError {
+    #[error("Message of {0} bytes is over the limit of {1}")]
+    MessageTooLong(usize, usize),
+    #[error("Content of {0} characters is over the limit of {1}")]
+    ContentTooLong(usize, usize),
+    #[error("{0} tags are over the limit of {1}")]
+    TooManyTags(usize, usize),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RelayInformation {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub pubkey: Option<String>,
+    #[serde(default)]
+    pub contact: Option<String>,
+    #[serde(default)]
+    pub supported_nips: Vec<u64>,
+    #[serde(default)]
+    pub software: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub limitation: Option<RelayLimitation>,
+    #[serde(default)]
+    pub fetched_at: i64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RelayLimitation {
+    #[serde(default)]
+    pub max_message_length: Option<usize>,
+    #[serde(default)]
+    pub max_subscriptions: Option<usize>,
+    #[serde(default)]
+    pub max_filters: Option<usize>,
+    #[serde(default)]
+    pub max_limit: Option<usize>,
+    #[serde(default)]
+    pub max_subid_length: Option<usize>,
+    #[serde(default)]
+    pub max_event_tags: Option<usize>,
+    #[serde(default)]
+    pub max_content_length: Option<usize>,
+    #[serde(default)]
+    pub min_pow_difficulty: Option<u8>,
+    #[serde(default)]
+    pub auth_required: bool,
+    #[serde(default)]
+    pub payment_required: bool,
+}
+
+impl RelayLimitation {
+    //Relays drop events over their limits, better to know before sending
+    pub fn check_event(&self, ev: &Event) -> Result<(), LimitationError> {
+        if let Some(max) = self.max_message_length {
+            let len = ClientMessage::new_event(ev.clone()).to_json().len();
+            if len > max {
+                return Err(LimitationError::MessageTooLong(len, max));
+            }
+        }
+        if let Some(max) = self.max_content_length {
+            let len = ev.content.chars().count();
+            if len > max {
+                return Err(LimitationError::ContentTooLong(len, max));
+            }
+        }
+        if let Some(max) = self.max_event_tags {
+            let len = get_tags(ev).len();
+            if len > max {
+                return Err(LimitationError::TooManyTags(len, max));
+            }
+        }
+        Ok(())
+    }
+}
+
+//The document is served over HTTP(S) at the relay URL
+pub async fn fetch_relay_info(
+    client: &dyn HttpClient,
+    relay_url: &str,
+) -> Result<RelayInformation, Nip11Error> {
+    let mut url = Url::parse(relay_url).map_err(|_e| Nip11Error::InvalidUrl(relay_url.into()))?;
+    let scheme = match url.scheme() {
+        "wss" => "https",
+        "ws" => "http",
+        _ => return Err(Nip11Error::InvalidUrl(relay_url.into())),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_e| Nip11Error::InvalidUrl(relay_url.into()))?;
+
+    let body = client.get(&url, "application/nostr+json").await?;
+    let mut info: RelayInformation =
+        serde_json::from_str(&body).map_err(|e| Nip11Error::InvalidDocument(e.to_string()))?;
+    info.fetched_at = chrono::offset::Utc::now().timestamp();
+    Ok(info)
+}
+
+//Cache of relays' information documents, persisted next to the config file
+pub struct RelayInfos {
+    infos: HashMap<String, RelayInformation>,
+}
+
+impl RelayInfos {
+    pub fn load() -> Self {
+        let path = Self::get_relay_infos_path();
+        info!("Loading relay infos from file {}", path.display());
+
+        let infos = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+                warn!("Can't parse relay infos file: {}", e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self { infos }
+    }
+
+    pub fn get(&self, relay_url: &str) -> Option<RelayInformation> {
+        self.infos.get(relay_url).cloned()
+    }
+
+    pub fn is_stale(&self, relay_url: &str) -> bool {
+        match self.infos.get(relay_url) {
+            Some(info) => info.fetched_at + RELAY_INFO_TTL < chrono::offset::Utc::now().timestamp(),
+            None => true,
+        }
+    }
+
+    pub fn insert(&mut self, relay_url: &str, info: RelayInformation) -> Result<(), Error> {
+        self.infos.insert(relay_url.into(), info);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let serialized = serde_json::to_string_pretty(&self.infos)?;
+        std::fs::write(Self::get_relay_infos_path(), serialized)
+    }
+
+    fn get_relay_infos_path() -> PathBuf {
+        let mut path = ConfigProvider::get_path();
+        path.push(RELAY_INFOS_FILENAME);
+        path
+    }
+}
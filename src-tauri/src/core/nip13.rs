@@ -0,0 +1,218 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use nostr::{Event, Keys};
+use thiserror::Error;
+
+use super::event::{gen_id, get_kind, get_tags, new_signed_event_at, EventError};
+
+//NIP-13 proof of work
+//https://github.com/nostr-protocol/nips/blob/master/13.md
+
+//Difficulties asked by relays are capped, past it mining takes hours
+pub const MAX_POW_DIFFICULTY: u8 = 32;
+//Mining is given up after this
+const POW_TIMEOUT: Duration = Duration::from_secs(120);
+//Prefix of OK messages refused for lack of work
+const POW_PREFIX: &str = "pow:";
+
+#[derive(Debug, Error)]
+pub enum PowError {
+    #[error(transparent)]
+    InvalidEvent(#[from] EventError),
+    #[error("Proof of work cancelled")]
+    Cancelled,
+    #[error("Proof of work of difficulty {0} timed out")]
+    TimedOut(u8),
+}
+
+pub fn leading_zero_bits(id: &[u8]) -> u8 {
+    let mut bits = 0;
+    for byte in id {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros() as u8;
+            break;
+        }
+    }
+    bits
+}
+
+pub fn get_difficulty(ev: &Event) -> u8 {
+    let id = ev.id.to_string();
+    let bytes: Vec<u8> = (0..id.len() / 2)
+        .filter_map(|i| u8::from_str_radix(&id[2 * i..2 * i + 2], 16).ok())
+        .collect();
+    leading_zero_bits(&bytes)
+}
+
+//Difficulty a relay asks for in its rejection, e.g. "pow: difficulty 20<24"
+pub fn requested_difficulty(message: &str) -> Option<u8> {
+    let reason = message.strip_prefix(POW_PREFIX)?;
+    reason
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|n| n.parse::<u8>().ok())
+        .max()
+        .map(|d| d.min(MAX_POW_DIFFICULTY))
+}
+
+//Tries nonces until the id has `difficulty` leading zero bits. `cancel` is
+//checked between attempts
+pub fn mine(
+    keys: &Keys,
+    created_at: i64,
+    kind: u64,
+    mut tags: Vec<Vec<String>>,
+    content: &str,
+    difficulty: u8,
+    cancel: &AtomicBool,
+) -> Result<Event, PowError> {
+    tags.retain(|t| t.first().map_or(true, |name| name != "nonce"));
+    tags.push(vec!["nonce".into(), "0".into(), difficulty.to_string()]);
+    let last = tags.len() - 1;
+    let mut nonce: u64 = 0;
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(PowError::Cancelled);
+        }
+        tags[last][1] = nonce.to_string();
+        let id = gen_id(&keys.public_key, created_at, kind, &tags, content);
+        if leading_zero_bits(&id) >= difficulty {
+            return Ok(new_signed_event_at(keys, created_at, kind, tags, content)?);
+        }
+        nonce += 1;
+    }
+}
+
+//Cancels the minings in progress, those of sent events and the retries
+//in the background. It is shared with the cancel command, which doesn't
+//wait on the broker busy awaiting the mining
+#[derive(Clone, Default)]
+pub struct PowCanceller(Arc<Mutex<Vec<Arc<AtomicBool>>>>);
+
+impl PowCanceller {
+    //Returns false if nothing was being mined
+    pub fn cancel(&self) -> bool {
+        match self.0.lock() {
+            Ok(current) => {
+                for cancel in current.iter() {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+                !current.is_empty()
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn add(&self, cancel: Arc<AtomicBool>) {
+        if let Ok(mut current) = self.0.lock() {
+            current.push(cancel);
+        }
+    }
+
+    fn remove(&self, cancel: &Arc<AtomicBool>) {
+        if let Ok(mut current) = self.0.lock() {
+            current.retain(|c| !Arc::ptr_eq(c, cancel));
+        }
+    }
+}
+
+//Stops the mining thread when the future awaiting it is dropped
+struct CancelOnDrop<'a>(Arc<AtomicBool>, &'a PowCanceller);
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+        self.1.remove(&self.0);
+    }
+}
+
+//Mines a copy of `ev`, signed with `keys`, on a blocking thread so the
+//async executor isn't stalled. Its timestamp is kept, gift wraps have
+//theirs randomized
+pub async fn mine_event(
+    keys: &Keys,
+    ev: &Event,
+    difficulty: u8,
+    canceller: &PowCanceller,
+) -> Result<Event, PowError> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    canceller.add(cancel.clone());
+    let _guard = CancelOnDrop(cancel.clone(), canceller);
+    let keys = keys.clone();
+    let created_at = ev.created_at.timestamp();
+    let kind = get_kind(ev);
+    let tags = get_tags(ev);
+    let content = ev.content.clone();
+    let mining = tokio::task::spawn_blocking(move || {
+        mine(&keys, created_at, kind, tags, &content, difficulty, &cancel)
+    });
+    match tokio::time::timeout(POW_TIMEOUT, mining).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(_join_error)) => Err(PowError::Cancelled),
+        Err(_) => Err(PowError::TimedOut(difficulty)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::new_signed_event_at;
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x0f]), 12);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x01]), 23);
+    }
+
+    #[test]
+    fn parses_requested_difficulty() {
+        assert_eq!(requested_difficulty("pow: difficulty 20<24"), Some(24));
+        assert_eq!(
+            requested_difficulty("pow: difficulty 99"),
+            Some(MAX_POW_DIFFICULTY)
+        );
+        assert_eq!(requested_difficulty("blocked: spam"), None);
+    }
+
+    #[tokio::test]
+    async fn mines_keeping_timestamp() {
+        let keys = Keys::generate_from_os_random().unwrap();
+        let ev = new_signed_event_at(&keys, 1_700_000_000, 1, vec![], "hello").unwrap();
+        let mined = mine_event(&keys, &ev, 8, &PowCanceller::default())
+            .await
+            .unwrap();
+        assert!(get_difficulty(&mined) >= 8);
+        assert_eq!(mined.created_at.timestamp(), 1_700_000_000);
+        let nonce = &get_tags(&mined)[0];
+        assert_eq!((nonce[0].as_str(), nonce[2].as_str()), ("nonce", "8"));
+    }
+
+    #[tokio::test]
+    async fn cancels_mining_in_progress() {
+        let canceller = PowCanceller::default();
+        assert!(!canceller.cancel());
+
+        let keys = Keys::generate_from_os_random().unwrap();
+        let ev = new_signed_event_at(&keys, 1_700_000_000, 1, vec![], "hello").unwrap();
+        let cancel = {
+            let canceller = canceller.clone();
+            tokio::spawn(async move {
+                while !canceller.cancel() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+        let res = mine_event(&keys, &ev, MAX_POW_DIFFICULTY, &canceller).await;
+        assert!(matches!(res, Err(PowError::Cancelled)));
+        cancel.await.unwrap();
+        assert!(!canceller.cancel());
+    }
+}
@@ -0,0 +1,242 @@
+use nostr::{Event, Keys};
+use rand::Rng;
+use secp256k1::{schnorrsig::PublicKey, SecretKey};
+use std::str::FromStr;
+use thiserror::Error;
+
+use super::{
+    event::{get_kind, new_signed_event_at, EventError, UnsignedEvent},
+    nip13::{mine_event, PowCanceller, PowError},
+    nip44::{self, Nip44Error},
+};
+
+//NIP-17 private direct messages: a kind 14 rumor is sealed (kind 13, signed
+//by the sender) and gift wrapped (kind 1059, signed by a random key) so
+//relays can't see who is talking to whom or when
+//https://github.com/nostr-protocol/nips/blob/master/17.md
+//https://github.com/nostr-protocol/nips/blob/master/59.md
+
+pub const KIND_SEAL: u64 = 13;
+pub const KIND_PRIVATE_DM: u64 = 14;
+pub const KIND_GIFT_WRAP: u64 = 1059;
+
+//Seal and gift wrap timestamps are randomized up to two days in the past
+const MAX_TIMESTAMP_TWEAK: i64 = 2 * 24 * 60 * 60;
+
+#[derive(Debug, Error)]
+pub enum Nip17Error {
+    #[error("Missing secret key")]
+    MissingSecretKey,
+    #[error("Invalid event: `{0}`")]
+    InvalidEvent(String),
+    #[error("Unexpected kind: `{0}`")]
+    UnexpectedKind(u64),
+    #[error("Seal author doesn't match the rumor author")]
+    AuthorMismatch,
+    #[error("Encryption error: `{0}`")]
+    Encryption(#[from] Nip44Error),
+    #[error("Event error: `{0}`")]
+    Event(#[from] EventError),
+    #[error(transparent)]
+    Pow(#[from] PowError),
+}
+
+pub fn new_rumor(
+    sender_pk: PublicKey,
+    receivers: &[PublicKey],
+    content: &str,
+    extra_tags: Vec<Vec<String>>,
+) -> UnsignedEvent {
+    let mut tags: Vec<Vec<String>> = receivers
+        .iter()
+        .map(|pk| vec!["p".into(), pk.to_string()])
+        .collect();
+    tags.extend(extra_tags);
+    UnsignedEvent::new(sender_pk, KIND_PRIVATE_DM, tags, content)
+}
+
+//Seals the rumor with the sender keys and wraps it to `receiver_pk`.
+//Senders wrap a copy to themselves too, so their own messages can be
+//fetched back from relays. The wrap is mined with its throwaway key when
+//relays ask for proof of work
+pub async fn gift_wrap(
+    sender_keys: &Keys,
+    receiver_pk: &PublicKey,
+    rumor: &UnsignedEvent,
+    difficulty: u8,
+    canceller: &PowCanceller,
+) -> Result<Event, Nip17Error> {
+    let sender_sk = sender_keys
+        .secret_key()
+        .map_err(|_e| Nip17Error::MissingSecretKey)?;
+    let rumor_json =
+        serde_json::to_string(rumor).map_err(|e| Nip17Error::InvalidEvent(e.to_string()))?;
+    let seal = new_signed_event_at(
+        sender_keys,
+        random_past_timestamp(),
+        KIND_SEAL,
+        vec![],
+        &nip44::encrypt(
+            &nip44::get_conversation_key(&sender_sk, receiver_pk)?,
+            &rumor_json,
+        )?,
+    )?;
+
+    let wrapper_keys =
+        Keys::generate_from_os_random().map_err(|e| Nip17Error::InvalidEvent(e.to_string()))?;
+    let wrapper_sk = wrapper_keys
+        .secret_key()
+        .map_err(|_e| Nip17Error::MissingSecretKey)?;
+    let seal_json =
+        serde_json::to_string(&seal).map_err(|e| Nip17Error::InvalidEvent(e.to_string()))?;
+    let wrap = new_signed_event_at(
+        &wrapper_keys,
+        random_past_timestamp(),
+        KIND_GIFT_WRAP,
+        vec![vec!["p".into(), receiver_pk.to_string()]],
+        &nip44::encrypt(
+            &nip44::get_conversation_key(&wrapper_sk, receiver_pk)?,
+            &seal_json,
+        )?,
+    )?;
+    if difficulty > 0 {
+        return Ok(mine_event(&wrapper_keys, &wrap, difficulty, canceller).await?);
+    }
+    Ok(wrap)
+}
+
+pub fn unwrap_gift(receiver_sk: &SecretKey, wrap: &Event) -> Result<UnsignedEvent, Nip17Error> {
+    if get_kind(wrap) != KIND_GIFT_WRAP {
+        return Err(Nip17Error::UnexpectedKind(get_kind(wrap)));
+    }
+    let seal_json = nip44::decrypt(
+        &nip44::get_conversation_key(receiver_sk, &wrap.pubkey)?,
+        &wrap.content,
+    )?;
+    let seal: Event =
+        serde_json::from_str(&seal_json).map_err(|e| Nip17Error::InvalidEvent(e.to_string()))?;
+    seal.verify()
+        .map_err(|e| Nip17Error::InvalidEvent(e.to_string()))?;
+    if get_kind(&seal) != KIND_SEAL {
+        return Err(Nip17Error::UnexpectedKind(get_kind(&seal)));
+    }
+
+    let rumor_json = nip44::decrypt(
+        &nip44::get_conversation_key(receiver_sk, &seal.pubkey)?,
+        &seal.content,
+    )?;
+    let rumor: UnsignedEvent =
+        serde_json::from_str(&rumor_json).map_err(|e| Nip17Error::InvalidEvent(e.to_string()))?;
+    //Only the seal is signed, so the rumor must claim the same author
+    if rumor.pubkey != seal.pubkey {
+        return Err(Nip17Error::AuthorMismatch);
+    }
+    if !rumor.verify_id() {
+        return Err(Nip17Error::InvalidEvent(format!(
+            "Invalid rumor id {}",
+            rumor.id
+        )));
+    }
+    Ok(rumor)
+}
+
+//Returns everyone in the room but `user_pk`: the author and every `p` tag
+pub fn get_rumor_participants(rumor: &UnsignedEvent, user_pk: &PublicKey) -> Vec<PublicKey> {
+    let mut participants = vec![rumor.pubkey];
+    participants.extend(
+        rumor
+            .get_tag_values("p")
+            .iter()
+            .filter_map(|pk| PublicKey::from_str(pk).ok()),
+    );
+    participants.retain(|pk| pk != user_pk);
+    participants.sort_by_key(|pk| pk.to_string());
+    participants.dedup();
+    participants
+}
+
+fn random_past_timestamp() -> i64 {
+    chrono::offset::Utc::now().timestamp() - rand::thread_rng().gen_range(0..MAX_TIMESTAMP_TWEAK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_keys() -> Keys {
+        Keys::generate_from_os_random().unwrap()
+    }
+
+    async fn wrap(sender: &Keys, receiver: &Keys, rumor: &UnsignedEvent) -> Event {
+        gift_wrap(
+            sender,
+            &receiver.public_key,
+            rumor,
+            0,
+            &PowCanceller::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unwraps_gift_wraps() {
+        let (sender, receiver) = (new_keys(), new_keys());
+        let rumor = new_rumor(sender.public_key, &[receiver.public_key], "hello", vec![]);
+        let wrap = wrap(&sender, &receiver, &rumor).await;
+        assert_eq!(get_kind(&wrap), KIND_GIFT_WRAP);
+        assert_ne!(wrap.pubkey, sender.public_key);
+
+        let unwrapped = unwrap_gift(&receiver.secret_key().unwrap(), &wrap).unwrap();
+        assert_eq!(unwrapped, rumor);
+        //Only the receiver can open it
+        assert!(unwrap_gift(&new_keys().secret_key().unwrap(), &wrap).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_rumors_of_another_author() {
+        let (mallory, alice, receiver) = (new_keys(), new_keys(), new_keys());
+        let rumor = new_rumor(alice.public_key, &[receiver.public_key], "hello", vec![]);
+        let wrap = wrap(&mallory, &receiver, &rumor).await;
+        assert!(matches!(
+            unwrap_gift(&receiver.secret_key().unwrap(), &wrap),
+            Err(Nip17Error::AuthorMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_rumors() {
+        let (sender, receiver) = (new_keys(), new_keys());
+        let mut rumor = new_rumor(sender.public_key, &[receiver.public_key], "hello", vec![]);
+        rumor.content = "goodbye".into();
+        let wrap = wrap(&sender, &receiver, &rumor).await;
+        assert!(matches!(
+            unwrap_gift(&receiver.secret_key().unwrap(), &wrap),
+            Err(Nip17Error::InvalidEvent(_))
+        ));
+    }
+
+    #[test]
+    fn lists_participants_but_the_user() {
+        let (user, alice, bob) = (new_keys(), new_keys(), new_keys());
+        let rumor = new_rumor(
+            alice.public_key,
+            &[user.public_key, bob.public_key, bob.public_key],
+            "hello",
+            vec![],
+        );
+        let sorted = |mut pks: Vec<PublicKey>| {
+            pks.sort_by_key(|pk| pk.to_string());
+            pks
+        };
+        assert_eq!(
+            get_rumor_participants(&rumor, &user.public_key),
+            sorted(vec![alice.public_key, bob.public_key])
+        );
+        //Our own messages are between us and the `p` tags
+        assert_eq!(
+            get_rumor_participants(&rumor, &alice.public_key),
+            sorted(vec![user.public_key, bob.public_key])
+        );
+    }
+}
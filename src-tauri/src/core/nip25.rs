@@ -0,0 +1,34 @@
+use secp256k1::schnorrsig::PublicKey;
+
+use super::event::UnsignedEvent;
+
+//NIP-25 reactions. In conversations they are sent as rumors gift wrapped
+//like NIP-17 messages, so they are as private as the messages
+//https://github.com/nostr-protocol/nips/blob/master/25.md
+
+pub const KIND_REACTION: u64 = 7;
+//Reactions are a like or a short text, usually an emoji. Longer contents
+//are ignored. Keep `CoreTaskHandleError::InvalidReaction` in sync
+const MAX_REACTION_LENGTH: usize = 32;
+const LIKE: &str = "+";
+
+//The reacted message, its author and its kind
+pub fn reaction_tags(message_id: &str, author: &PublicKey, kind: u64) -> Vec<Vec<String>> {
+    vec![
+        vec!["e".into(), message_id.into()],
+        vec!["p".into(), author.to_string()],
+        vec!["k".into(), kind.to_string()],
+    ]
+}
+
+//Id of the reacted message and the reaction. The last `e` tag is the
+//reacted event, an empty content is a like
+pub fn parse_reaction(rumor: &UnsignedEvent) -> Option<(String, String)> {
+    let message_id = rumor.get_tag_values("e").pop()?;
+    let reaction = match rumor.content.trim() {
+        "" => LIKE.to_string(),
+        content if content.chars().count() <= MAX_REACTION_LENGTH => content.to_string(),
+        _ => return None,
+    };
+    Some((message_id, reaction))
+}
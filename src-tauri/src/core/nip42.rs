@@ -0,0 +1,23 @@
+use nostr::{Event, Keys};
+
+use super::event::{new_signed_event, EventError};
+
+//NIP-42 authentication of clients to relays
+//https://github.com/nostr-protocol/nips/blob/master/42.md
+
+pub const KIND_AUTH: u64 = 22242;
+//Prefix of OK and CLOSED messages refused until we authenticate
+const AUTH_REQUIRED_PREFIX: &str = "auth-required:";
+
+//Answer to the relay's AUTH challenge, only sent to that relay
+pub fn new_auth_event(keys: &Keys, relay_url: &str, challenge: &str) -> Result<Event, EventError> {
+    let tags = vec![
+        vec!["relay".to_string(), relay_url.to_string()],
+        vec!["challenge".to_string(), challenge.to_string()],
+    ];
+    new_signed_event(keys, KIND_AUTH, tags, "")
+}
+
+pub fn is_auth_required(message: &str) -> bool {
+    message.starts_with(AUTH_REQUIRED_PREFIX)
+}
@@ -0,0 +1,399 @@
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use secp256k1::{ecdh::SharedSecret, schnorrsig, SecretKey};
+use sha2::Sha256;
+use thiserror::Error;
+
+//NIP-44 v2 versioned encryption
+//https://github.com/nostr-protocol/nips/blob/master/44.md
+
+const VERSION: u8 = 2;
+const SALT: &[u8] = b"nip44-v2";
+const MIN_PLAINTEXT_SIZE: usize = 1;
+const MAX_PLAINTEXT_SIZE: usize = 65535;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum Nip44Error {
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+    #[error("Invalid plaintext length: `{0}`")]
+    InvalidPlaintextLength(usize),
+    #[error("Invalid payload length: `{0}`")]
+    InvalidPayloadLength(usize),
+    #[error("Unknown encryption version: `{0}`")]
+    UnknownVersion(u8),
+    #[error("Invalid base64 payload")]
+    InvalidBase64,
+    #[error("Invalid MAC")]
+    InvalidMac,
+    #[error("Invalid padding")]
+    InvalidPadding,
+    #[error("Decrypted content is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+//The conversation key is symmetric, the same key is derived from
+//(sk_a, pk_b) and (sk_b, pk_a) and can be cached per contact
+pub fn get_conversation_key(
+    sk: &SecretKey,
+    pk: &schnorrsig::PublicKey,
+) -> Result<[u8; 32], Nip44Error> {
+    let mut compressed_pk = [0u8; 33];
+    compressed_pk[0] = 0x02;
+    compressed_pk[1..].copy_from_slice(&pk.serialize());
+    let pk = secp256k1::PublicKey::from_slice(&compressed_pk)
+        .map_err(|_e| Nip44Error::InvalidPublicKey)?;
+
+    //NIP-44 uses the unhashed x coordinate of the shared point
+    let shared_x = SharedSecret::new_with_hash(&pk, sk, |x, _y| x.into());
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(SALT), &shared_x[..32]);
+
+    let mut conversation_key = [0u8; 32];
+    conversation_key.copy_from_slice(&prk);
+    Ok(conversation_key)
+}
+
+pub fn encrypt(conversation_key: &[u8; 32], plaintext: &str) -> Result<String, Nip44Error> {
+    let mut nonce = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    encrypt_with_nonce(conversation_key, plaintext, &nonce)
+}
+
+pub fn encrypt_with_nonce(
+    conversation_key: &[u8; 32],
+    plaintext: &str,
+    nonce: &[u8; 32],
+) -> Result<String, Nip44Error> {
+    let (chacha_key, chacha_nonce, hmac_key) = get_message_keys(conversation_key, nonce);
+
+    let mut ciphertext = pad(plaintext)?;
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut ciphertext);
+    let mac = hmac_aad(&hmac_key, &ciphertext, nonce);
+
+    let mut payload = Vec::with_capacity(1 + nonce.len() + ciphertext.len() + mac.len());
+    payload.push(VERSION);
+    payload.extend_from_slice(nonce);
+    payload.extend_from_slice(&ciphertext);
+    payload.extend_from_slice(&mac);
+    Ok(base64::encode(payload))
+}
+
+pub fn decrypt(conversation_key: &[u8; 32], payload: &str) -> Result<String, Nip44Error> {
+    //'#' is reserved to flag future, non base64 encoded versions
+    if payload.starts_with('#') {
+        return Err(Nip44Error::UnknownVersion(payload.as_bytes()[0]));
+    }
+    if payload.len() < 132 || payload.len() > 87472 {
+        return Err(Nip44Error::InvalidPayloadLength(payload.len()));
+    }
+
+    let data = base64::decode(payload).map_err(|_e| Nip44Error::InvalidBase64)?;
+    if data.len() < 99 || data.len() > 65603 {
+        return Err(Nip44Error::InvalidPayloadLength(data.len()));
+    }
+    if data[0] != VERSION {
+        return Err(Nip44Error::UnknownVersion(data[0]));
+    }
+
+    let mut nonce = [0u8; 32];
+    nonce.copy_from_slice(&data[1..33]);
+    let mut ciphertext = data[33..data.len() - 32].to_vec();
+    let mac = &data[data.len() - 32..];
+
+    let (chacha_key, chacha_nonce, hmac_key) = get_message_keys(conversation_key, &nonce);
+
+    let mut hmac =
+        Hmac::<Sha256>::new_from_slice(&hmac_key).expect("HMAC can take key of any size");
+    hmac.update(&nonce);
+    hmac.update(&ciphertext);
+    hmac.verify_slice(mac)
+        .map_err(|_e| Nip44Error::InvalidMac)?;
+
+    ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut ciphertext);
+    unpad(&ciphertext)
+}
+
+//Cheap check used to tell NIP-44 payloads apart from NIP-04 ones
+//(`<base64>?iv=<base64>`) before trying to decrypt
+pub fn is_nip44_payload(content: &str) -> bool {
+    if content.contains("?iv=") || content.len() < 132 {
+        return false;
+    }
+    match content.get(..4).map(base64::decode) {
+        Some(Ok(prefix)) => prefix.first() == Some(&VERSION),
+        _ => false,
+    }
+}
+
+fn get_message_keys(
+    conversation_key: &[u8; 32],
+    nonce: &[u8; 32],
+) -> ([u8; 32], [u8; 12], [u8; 32]) {
+    let hkdf = Hkdf::<Sha256>::from_prk(conversation_key).expect("Conversation key has PRK size");
+    let mut keys = [0u8; 76];
+    hkdf.expand(nonce, &mut keys)
+        .expect("76 bytes is a valid HKDF-SHA256 output length");
+
+    let mut chacha_key = [0u8; 32];
+    let mut chacha_nonce = [0u8; 12];
+    let mut hmac_key = [0u8; 32];
+    chacha_key.copy_from_slice(&keys[0..32]);
+    chacha_nonce.copy_from_slice(&keys[32..44]);
+    hmac_key.copy_from_slice(&keys[44..76]);
+    (chacha_key, chacha_nonce, hmac_key)
+}
+
+fn hmac_aad(key: &[u8; 32], message: &[u8], aad: &[u8; 32]) -> [u8; 32] {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    hmac.update(aad);
+    hmac.update(message);
+
+    let mut mac = [0u8; 32];
+    mac.copy_from_slice(&hmac.finalize().into_bytes());
+    mac
+}
+
+fn calc_padded_len(unpadded_len: usize) -> usize {
+    if unpadded_len <= 32 {
+        return 32;
+    }
+    let next_power = 1 << (usize::BITS - (unpadded_len - 1).leading_zeros());
+    let chunk = if next_power <= 256 {
+        32
+    } else {
+        next_power / 8
+    };
+    chunk * ((unpadded_len - 1) / chunk + 1)
+}
+
+fn pad(plaintext: &str) -> Result<Vec<u8>, Nip44Error> {
+    let unpadded = plaintext.as_bytes();
+    let unpadded_len = unpadded.len();
+    if !(MIN_PLAINTEXT_SIZE..=MAX_PLAINTEXT_SIZE).contains(&unpadded_len) {
+        return Err(Nip44Error::InvalidPlaintextLength(unpadded_len));
+    }
+
+    let mut padded = Vec::with_capacity(2 + calc_padded_len(unpadded_len));
+    padded.extend_from_slice(&(unpadded_len as u16).to_be_bytes());
+    padded.extend_from_slice(unpadded);
+    padded.resize(2 + calc_padded_len(unpadded_len), 0);
+    Ok(padded)
+}
+
+fn unpad(padded: &[u8]) -> Result<String, Nip44Error> {
+    if padded.len() < 2 {
+        return Err(Nip44Error::InvalidPadding);
+    }
+    let unpadded_len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if unpadded_len < MIN_PLAINTEXT_SIZE
+        || padded.len() != 2 + calc_padded_len(unpadded_len)
+        || padded.len() < 2 + unpadded_len
+    {
+        return Err(Nip44Error::InvalidPadding);
+    }
+
+    String::from_utf8(padded[2..2 + unpadded_len].to_vec()).map_err(|_e| Nip44Error::InvalidUtf8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    //Vectors from https://github.com/paulmillr/nip44/blob/main/nip44.vectors.json
+    fn hex_to_array(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    fn public_key(sk: &SecretKey) -> schnorrsig::PublicKey {
+        let secp = secp256k1::Secp256k1::new();
+        schnorrsig::PublicKey::from_keypair(
+            &secp,
+            &schnorrsig::KeyPair::from_secret_key(&secp, *sk),
+        )
+    }
+
+    #[test]
+    fn conversation_key() {
+        let vectors = [
+            (
+                "315e59ff51cb9209768cf7da80791ddcaae56ac9775eb25b6dee1234bc5d2268",
+                "c2f9d9948dc8c7c38321e4b85c8558872eafa0641cd269db76848a6073e69133",
+                "3dfef0ce2a4d80a25e7a328accf73448ef67096f65f79588e358d9a0eb9013f1",
+            ),
+            (
+                "a1e37752c9fdc1273be53f68c5f74be7c8905728e8de75800b94262f9497c86e",
+                "03bb7947065dde12ba991ea045132581d0954f042c84e06d8c00066e23c1a800",
+                "4d14f36e81b8452128da64fe6f1eae873baae2f444b02c950b90e43553f2178b",
+            ),
+        ];
+        for (sk, pk, conversation_key) in vectors {
+            let sk = SecretKey::from_str(sk).unwrap();
+            let pk = schnorrsig::PublicKey::from_str(pk).unwrap();
+            assert_eq!(
+                get_conversation_key(&sk, &pk).unwrap(),
+                hex_to_array(conversation_key)
+            );
+        }
+    }
+
+    #[test]
+    fn conversation_key_is_symmetric() {
+        let sk1 = SecretKey::from_str(&format!("{:064x}", 1)).unwrap();
+        let sk2 = SecretKey::from_str(&format!("{:064x}", 2)).unwrap();
+        assert_eq!(
+            get_conversation_key(&sk1, &public_key(&sk2)).unwrap(),
+            get_conversation_key(&sk2, &public_key(&sk1)).unwrap()
+        );
+    }
+
+    #[test]
+    fn padded_len() {
+        let vectors = [
+            (16, 32),
+            (32, 32),
+            (33, 64),
+            (37, 64),
+            (45, 64),
+            (49, 64),
+            (64, 64),
+            (65, 96),
+            (100, 128),
+            (111, 128),
+            (200, 224),
+            (250, 256),
+            (320, 320),
+            (383, 384),
+            (384, 384),
+            (400, 448),
+            (500, 512),
+            (512, 512),
+            (515, 640),
+            (700, 768),
+            (800, 896),
+            (900, 1024),
+            (1020, 1024),
+            (65536, 65536),
+        ];
+        for (len, padded_len) in vectors {
+            assert_eq!(calc_padded_len(len), padded_len, "unpadded length {}", len);
+        }
+    }
+
+    #[test]
+    fn encrypt_decrypt() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "a",
+                "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb",
+            ),
+            (
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d",
+                "f00000000000000000000000000000f00000000000000000000000000000000f",
+                "🍕🫃",
+                "AvAAAAAAAAAAAAAAAAAAAPAAAAAAAAAAAAAAAAAAAAAPSKSK6is9ngkX2+cSq85Th16oRTISAOfhStnixqZziKMDvB0QQzgFZdjLTPicCJaV8nDITO+QfaQ61+KbWQIOO2Yj",
+            ),
+        ];
+        for (sk1, sk2, conversation_key, nonce, plaintext, payload) in vectors {
+            let sk1 = SecretKey::from_str(sk1).unwrap();
+            let sk2 = SecretKey::from_str(sk2).unwrap();
+            let key = get_conversation_key(&sk1, &public_key(&sk2)).unwrap();
+            assert_eq!(key, hex_to_array(conversation_key));
+            let encrypted = encrypt_with_nonce(&key, plaintext, &hex_to_array(nonce)).unwrap();
+            assert_eq!(encrypted, payload);
+            assert!(is_nip44_payload(&encrypted));
+            assert_eq!(decrypt(&key, payload).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn invalid_plaintext_length() {
+        let key = [1u8; 32];
+        assert_eq!(
+            encrypt(&key, ""),
+            Err(Nip44Error::InvalidPlaintextLength(0))
+        );
+        assert_eq!(
+            encrypt(&key, &"a".repeat(65536)),
+            Err(Nip44Error::InvalidPlaintextLength(65536))
+        );
+    }
+
+    #[test]
+    fn decrypt_invalid_mac() {
+        let key = [1u8; 32];
+        let payload = encrypt(&key, "hello").unwrap();
+        let mut data = base64::decode(&payload).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert_eq!(
+            decrypt(&key, &base64::encode(&data)),
+            Err(Nip44Error::InvalidMac)
+        );
+        //The MAC also covers the nonce
+        let mut data = base64::decode(&payload).unwrap();
+        data[1] ^= 1;
+        assert_eq!(
+            decrypt(&key, &base64::encode(&data)),
+            Err(Nip44Error::InvalidMac)
+        );
+        let other_key = [2u8; 32];
+        assert_eq!(decrypt(&other_key, &payload), Err(Nip44Error::InvalidMac));
+    }
+
+    #[test]
+    fn decrypt_invalid_padding() {
+        let key = [1u8; 32];
+        let nonce = [3u8; 32];
+        //Properly authenticated payload whose length prefix doesn't
+        //match its padding
+        let mut padded = pad("hello").unwrap();
+        padded[1] = 40;
+        let (chacha_key, chacha_nonce, hmac_key) = get_message_keys(&key, &nonce);
+        ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut padded);
+        let mac = hmac_aad(&hmac_key, &padded, &nonce);
+        let mut payload = vec![VERSION];
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&padded);
+        payload.extend_from_slice(&mac);
+        assert_eq!(
+            decrypt(&key, &base64::encode(&payload)),
+            Err(Nip44Error::InvalidPadding)
+        );
+    }
+
+    #[test]
+    fn decrypt_invalid_payload() {
+        let key = [1u8; 32];
+        let payload = encrypt(&key, "hello").unwrap();
+        assert_eq!(
+            decrypt(&key, "#Atqupco0WyaOW2IGDKcshwxI9xO8HgD/P8Ddt46CbxDbrhdG8VmJZE0UICD06CUvEvdnr1cp1fiMtlM/GrE92xAc1EwsVCQEgWEu2gsHUVf4JAa3TpgkmFc3TWsax0v6n/Wq"),
+            Err(Nip44Error::UnknownVersion(b'#'))
+        );
+        assert_eq!(
+            decrypt(&key, &payload[..100]),
+            Err(Nip44Error::InvalidPayloadLength(100))
+        );
+        let mut data = base64::decode(&payload).unwrap();
+        data[0] = 1;
+        assert_eq!(
+            decrypt(&key, &base64::encode(&data)),
+            Err(Nip44Error::UnknownVersion(1))
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use log::{info, warn};
+use nostr::Event;
+use secp256k1::schnorrsig::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Error},
+    path::PathBuf,
+};
+use thiserror::Error;
+
+use super::{
+    config::{ConfigProvider, RelayConfig},
+    event::{get_kind, get_tags},
+    relay_pool::normalize_relay_url,
+};
+
+//NIP-65 relay lists and NIP-17 DM relay lists
+//https://github.com/nostr-protocol/nips/blob/master/65.md
+
+pub const KIND_RELAY_LIST: u64 = 10002;
+pub const KIND_DM_RELAY_LIST: u64 = 10050;
+const RELAY_LISTS_FILENAME: &str = "relay_lists.json";
+
+#[derive(Debug, Error)]
+pub enum RelayListsError {
+    #[error("Unexpected kind: `{0}`")]
+    UnexpectedKind(u64),
+    #[error("Saving relay lists failed: `{0}`")]
+    SaveFailed(String),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RelayList {
+    //Relays where the user reads mentions and messages, its inbox
+    pub read: Vec<String>,
+    //Relays where the user publishes, its outbox
+    pub write: Vec<String>,
+    //Relays where the user wants to receive NIP-17 messages
+    pub dm: Vec<String>,
+    //Timestamps of the kind 10002 and 10050 events, used to keep only the newest
+    relay_list_at: i64,
+    dm_relay_list_at: i64,
+}
+
+//Cache of contacts' relay lists, persisted next to the config file
+pub struct RelayLists {
+    lists: HashMap<String, RelayList>,
+}
+
+impl RelayLists {
+    pub fn load() -> Self {
+        let path = Self::get_relay_lists_path();
+        info!("Loading relay lists from file {}", path.display());
+
+        let lists = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+                warn!("Can't parse relay lists file: {}", e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self { lists }
+    }
+
+    pub fn get(&self, pk: &PublicKey) -> Option<RelayList> {
+        self.lists.get(&pk.to_string()).cloned()
+    }
+
+    //Returns true if the event replaced the cached list
+    pub fn try_update_from_ev(&mut self, ev: &Event) -> Result<bool, RelayListsError> {
+        let kind = get_kind(ev);
+        let created_at = ev.created_at.timestamp();
+        let list = self.lists.entry(ev.pubkey.to_string()).or_default();
+        match kind {
+            KIND_RELAY_LIST => {
+                if list.relay_list_at >= created_at {
+                    return Ok(false);
+                }
+                let (read, write) = parse_relay_list(ev);
+                list.read = read;
+                list.write = write;
+                list.relay_list_at = created_at;
+            }
+            KIND_DM_RELAY_LIST => {
+                if list.dm_relay_list_at >= created_at {
+                    return Ok(false);
+                }
+                list.dm = parse_dm_relay_list(ev);
+                list.dm_relay_list_at = created_at;
+            }
+            _ => return Err(RelayListsError::UnexpectedKind(kind)),
+        }
+        self.save()
+            .map_err(|e| RelayListsError::SaveFailed(e.to_string()))?;
+        Ok(true)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let serialized = serde_json::to_string_pretty(&self.lists)?;
+        std::fs::write(Self::get_relay_lists_path(), serialized)
+    }
+
+    fn get_relay_lists_path() -> PathBuf {
+        let mut path = ConfigProvider::get_path();
+        path.push(RELAY_LISTS_FILENAME);
+        path
+    }
+}
+
+//["r", <url>, <"read" | "write">], relays without marker are both
+pub fn parse_relay_list(ev: &Event) -> (Vec<String>, Vec<String>) {
+    let mut read = vec![];
+    let mut write = vec![];
+    for tag in get_tags(ev) {
+        if tag.len() < 2 || tag[0] != "r" {
+            continue;
+        }
+        let url = match normalize_relay_url(&tag[1]) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        match tag.get(2).map(String::as_str) {
+            Some("read") => read.push(url),
+            Some("write") => write.push(url),
+            _ => {
+                read.push(url.clone());
+                write.push(url);
+            }
+        }
+    }
+    (read, write)
+}
+
+//["relay", <url>]
+pub fn parse_dm_relay_list(ev: &Event) -> Vec<String> {
+    get_tags(ev)
+        .into_iter()
+        .filter(|t| t.len() >= 2 && t[0] == "relay")
+        .filter_map(|t| normalize_relay_url(&t[1]).ok())
+        .collect()
+}
+
+pub fn relay_list_tags(relays: &[RelayConfig]) -> Vec<Vec<String>> {
+    relays
+        .iter()
+        .filter_map(|r| match (r.read, r.write) {
+            (true, true) => Some(vec!["r".into(), r.url.clone()]),
+            (true, false) => Some(vec!["r".into(), r.url.clone(), "read".into()]),
+            (false, true) => Some(vec!["r".into(), r.url.clone(), "write".into()]),
+            (false, false) => None,
+        })
+        .collect()
+}
+
+pub fn dm_relay_list_tags(relays: &[RelayConfig]) -> Vec<Vec<String>> {
+    relays
+        .iter()
+        .filter(|r| r.dm)
+        .map(|r| vec!["relay".into(), r.url.clone()])
+        .collect()
+}
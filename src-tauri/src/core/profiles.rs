@@ -0,0 +1,328 @@
+use log::{info, warn};
+use nostr::Event;
+use secp256k1::schnorrsig::PublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Error},
+    path::PathBuf,
+};
+use thiserror::Error;
+use tokio::sync::broadcast;
+
+use super::{
+    config::ConfigProvider,
+    event::{get_kind, UnsignedEvent},
+};
+
+const PROFILES_FILENAME: &str = "profiles.json";
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_ABOUT_LENGTH: usize = 1024;
+
+pub const KIND_METADATA: u64 = 0;
+
+#[derive(Debug, Error)]
+pub enum ProfilesError {
+    #[error("Unexpected kind: `{0}`")]
+    UnexpectedKind(u64),
+    #[error("Invalid metadata: `{0}`")]
+    InvalidMetadata(String),
+    #[error("Invalid `{0}`: {1}")]
+    InvalidField(&'static str, String),
+    #[error("Saving profiles failed: `{0}`")]
+    SaveFailed(String),
+    #[error("SendError")]
+    SendError,
+}
+
+#[derive(Clone, Debug)]
+pub enum ProfilesNotifications {
+    ProfileUpdated(Profile),
+}
+
+//Cache of kind 0 metadata, persisted next to the config file
+pub struct Profiles {
+    profiles: HashMap<String, Profile>,
+    noti_sender: broadcast::Sender<ProfilesNotifications>,
+}
+
+impl Profiles {
+    pub fn load() -> Self {
+        let (noti_sender, _) = broadcast::channel(64);
+        let path = Self::get_profiles_path();
+        info!("Loading profiles from file {}", path.display());
+
+        let mut profiles = HashMap::new();
+        if let Ok(file) = File::open(&path) {
+            let cached: Vec<Profile> = serde_json::from_reader(BufReader::new(file))
+                .unwrap_or_else(|e| {
+                    warn!("Can't parse profiles file: {}", e);
+                    vec![]
+                });
+            cached.into_iter().for_each(|p| {
+                profiles.insert(p.pk.to_string(), p);
+            });
+        }
+
+        Self {
+            profiles,
+            noti_sender,
+        }
+    }
+
+    pub fn get_notifications_ch(&self) -> broadcast::Receiver<ProfilesNotifications> {
+        self.noti_sender.subscribe()
+    }
+
+    pub fn get_profile(&self, pk: &str) -> Option<Profile> {
+        self.profiles.get(pk).cloned()
+    }
+
+    //Returns the profile if the event replaces the cached one
+    pub fn try_update_from_ev(&mut self, ev: &Event) -> Result<Option<Profile>, ProfilesError> {
+        if get_kind(ev) != KIND_METADATA {
+            return Err(ProfilesError::UnexpectedKind(get_kind(ev)));
+        }
+        let ev = UnsignedEvent::from_event(ev)
+            .map_err(|e| ProfilesError::InvalidMetadata(e.to_string()))?;
+        if let Some(cached) = self.profiles.get(&ev.pubkey.to_string()) {
+            if !cached.is_replaced_by(&ev) {
+                return Ok(None);
+            }
+        }
+
+        let profile = Profile::from_metadata(&ev)?;
+        self.profiles
+            .insert(profile.pk.to_string(), profile.clone());
+        self.save()
+            .map_err(|e| ProfilesError::SaveFailed(e.to_string()))?;
+
+        self.noti_sender
+            .send(ProfilesNotifications::ProfileUpdated(profile.clone()))
+            .map_err(|_e| ProfilesError::SendError)?;
+        Ok(Some(profile))
+    }
+
+    //Builds kind 0 content for `pk`, keeping fields set by other clients
+    pub fn build_metadata(
+        &self,
+        pk: &str,
+        update: &ProfileUpdate,
+    ) -> Result<String, ProfilesError> {
+        update.validate()?;
+        let metadata = self
+            .profiles
+            .get(pk)
+            .map(|p| p.metadata.clone())
+            .unwrap_or_default();
+        serde_json::to_string(&update.apply(metadata))
+            .map_err(|e| ProfilesError::InvalidMetadata(e.to_string()))
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let profiles: Vec<&Profile> = self.profiles.values().collect();
+        let serialized = serde_json::to_string_pretty(&profiles)?;
+        std::fs::write(Self::get_profiles_path(), serialized)
+    }
+
+    fn get_profiles_path() -> PathBuf {
+        let mut path = ConfigProvider::get_path();
+        path.push(PROFILES_FILENAME);
+        path
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub pk: PublicKey,
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+    pub picture: Option<String>,
+    pub about: Option<String>,
+    pub nip05: Option<String>,
+    //Timestamp and id of the metadata event, used to keep only the newest
+    pub created_at: i64,
+    #[serde(default)]
+    pub event_id: String,
+    //Whole kind 0 content, including fields we don't know about
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+}
+
+impl Profile {
+    fn from_metadata(ev: &UnsignedEvent) -> Result<Self, ProfilesError> {
+        let raw: Map<String, Value> = serde_json::from_str(&ev.content)
+            .map_err(|e| ProfilesError::InvalidMetadata(e.to_string()))?;
+        let metadata: Metadata = serde_json::from_value(Value::Object(raw.clone()))
+            .map_err(|e| ProfilesError::InvalidMetadata(e.to_string()))?;
+        Ok(Self {
+            pk: ev.pubkey,
+            name: non_empty(metadata.name),
+            display_name: non_empty(metadata.display_name),
+            picture: non_empty(metadata.picture),
+            about: non_empty(metadata.about),
+            nip05: non_empty(metadata.nip05),
+            created_at: ev.created_at,
+            event_id: ev.id.clone(),
+            metadata: raw,
+        })
+    }
+
+    //NIP-01 replaceable events: the newest one wins, the lowest id on ties
+    fn is_replaced_by(&self, ev: &UnsignedEvent) -> bool {
+        match ev.created_at.cmp(&self.created_at) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => ev.id < self.event_id,
+        }
+    }
+}
+
+//Edit of the user's own profile. Fields left as `None` are kept as they are,
+//empty strings remove the field
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProfileUpdate {
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+    pub picture: Option<String>,
+    pub about: Option<String>,
+    pub nip05: Option<String>,
+}
+
+impl ProfileUpdate {
+    pub fn validate(&self) -> Result<(), ProfilesError> {
+        if let Some(name) = non_empty(self.name.clone()) {
+            if name.chars().count() > MAX_NAME_LENGTH {
+                return Err(ProfilesError::InvalidField("name", "too long".into()));
+            }
+            if name.chars().any(char::is_whitespace) {
+                return Err(ProfilesError::InvalidField(
+                    "name",
+                    "must not contain whitespace".into(),
+                ));
+            }
+        }
+        if let Some(display_name) = non_empty(self.display_name.clone()) {
+            if display_name.chars().count() > MAX_NAME_LENGTH {
+                return Err(ProfilesError::InvalidField(
+                    "display_name",
+                    "too long".into(),
+                ));
+            }
+        }
+        if let Some(about) = non_empty(self.about.clone()) {
+            if about.chars().count() > MAX_ABOUT_LENGTH {
+                return Err(ProfilesError::InvalidField("about", "too long".into()));
+            }
+        }
+        if let Some(picture) = non_empty(self.picture.clone()) {
+            match url::Url::parse(&picture) {
+                Ok(url) if url.scheme() == "https" || url.scheme() == "http" => (),
+                _ => {
+                    return Err(ProfilesError::InvalidField(
+                        "picture",
+                        "must be an http(s) URL".into(),
+                    ))
+                }
+            }
+        }
+        if let Some(nip05) = non_empty(self.nip05.clone()) {
+            let valid = match nip05.split_once('@') {
+                Some((local, domain)) => {
+                    !local.is_empty()
+                        && local
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+                        && domain.contains('.')
+                        && url::Url::parse(&format!("https://{}", domain)).is_ok()
+                }
+                None => false,
+            };
+            if !valid {
+                return Err(ProfilesError::InvalidField(
+                    "nip05",
+                    "must look like name@domain".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, mut metadata: Map<String, Value>) -> Map<String, Value> {
+        let fields = [
+            ("name", &self.name),
+            ("display_name", &self.display_name),
+            ("picture", &self.picture),
+            ("about", &self.about),
+            ("nip05", &self.nip05),
+        ];
+        for (key, value) in fields {
+            match value.as_deref().map(str::trim) {
+                Some("") => {
+                    metadata.remove(key);
+                }
+                Some(value) => {
+                    metadata.insert(key.into(), Value::String(value.into()));
+                }
+                None => (),
+            }
+        }
+        //Legacy key written by some clients, superseded by display_name
+        if self.display_name.is_some() {
+            metadata.remove("displayName");
+        }
+        metadata
+    }
+}
+
+//Kind 0 content, unknown fields are ignored
+#[derive(Debug, Default, Deserialize)]
+struct Metadata {
+    name: Option<String>,
+    #[serde(alias = "displayName")]
+    display_name: Option<String>,
+    picture: Option<String>,
+    about: Option<String>,
+    nip05: Option<String>,
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    fn metadata_ev(pk: PublicKey, id: &str, created_at: i64) -> UnsignedEvent {
+        UnsignedEvent {
+            id: id.into(),
+            pubkey: pk,
+            created_at,
+            kind: KIND_METADATA,
+            tags: vec![],
+            content: r#"{"name":"bob"}"#.into(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_metadata() {
+        let pk = Keys::generate_from_os_random().unwrap().public_key;
+        let cached = Profile::from_metadata(&metadata_ev(pk, "bb", 100)).unwrap();
+        assert!(!cached.is_replaced_by(&metadata_ev(pk, "aa", 99)));
+        assert!(cached.is_replaced_by(&metadata_ev(pk, "cc", 101)));
+    }
+
+    #[test]
+    fn breaks_ties_by_lowest_id() {
+        let pk = Keys::generate_from_os_random().unwrap().public_key;
+        let cached = Profile::from_metadata(&metadata_ev(pk, "bb", 100)).unwrap();
+        assert!(!cached.is_replaced_by(&metadata_ev(pk, "bb", 100)));
+        assert!(!cached.is_replaced_by(&metadata_ev(pk, "cc", 100)));
+        assert!(cached.is_replaced_by(&metadata_ev(pk, "aa", 100)));
+    }
+}
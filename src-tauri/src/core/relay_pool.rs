@@ -1,31 +1,138 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use futures::{SinkExt, StreamExt};
 use log::{debug, error};
-use nostr::{self, ClientMessage, Event, Keys, RelayMessage, SubscriptionFilter};
+use nostr::{self, ClientMessage, Event, Keys, RelayMessage};
+use serde::Serialize;
+use thiserror::Error;
 use tokio::sync::{
     broadcast,
     mpsc::{self, Receiver, Sender},
+    oneshot,
 };
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::{client_async_tls, connect_async, tungstenite::Message};
+use url::Url;
+
+use super::{
+    config::{Contact, RelayConfig},
+    event::{get_kind, KIND_ENCRYPTED_DM},
+    event_cache::{EventCache, EventCacheMetrics, EventStore},
+    nip09::KIND_DELETION,
+    nip11::{LimitationError, RelayLimitation},
+    nip13::MAX_POW_DIFFICULTY,
+    nip17::KIND_GIFT_WRAP,
+    nip42::is_auth_required,
+    socks,
+    subscription::{Filter, Subscription},
+};
+
+//Time temporary connections stay open after the last message sent, to get
+//the relay's answer and be reused by the next events
+const TEMP_RELAY_TIMEOUT: Duration = Duration::from_secs(60);
+//Temporary connections open at once, events to other relays are not sent
+const MAX_TEMP_RELAYS: usize = 32;
+//Time a relay has to accept the websocket connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+//Tor circuits take longer to build
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+//Relays are pinged every interval. Connections that stay silent for the
+//pong timeout after a ping are dropped and opened again
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+//First delay before reconnecting, doubled after each failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+//Sent events kept to be retried when relays reject them
+const MAX_SENT_EVENTS: usize = 256;
+//Events waiting for the relay to authenticate us
+const MAX_PENDING_EVENTS: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum RelayUrlError {
+    #[error("Invalid relay URL: `{0}`")]
+    Invalid(String),
+    #[error("Relay URL must be ws:// or wss://: `{0}`")]
+    UnsupportedScheme(String),
+}
+
+#[derive(Debug, Error)]
+pub enum RelayPoolError {
+    #[error("No relay accepts the event: {}", format_rejections(.0))]
+    EventRejected(Vec<RelayRejection>),
+}
 
-use super::{config::Contact, subscription::Subscription};
+//A relay that won't get an event because it is over its limits
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayRejection {
+    pub relay_url: String,
+    pub reason: String,
+}
+
+fn format_rejections(rejections: &[RelayRejection]) -> String {
+    rejections
+        .iter()
+        .map(|r| format!("{} ({})", r.relay_url, r.reason))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+//Relays are keyed by URL, so the same relay has to be written the same way
+//everywhere. The canonical form has a lowercase host and a path, `/` at least
+pub fn normalize_relay_url(url: &str) -> Result<String, RelayUrlError> {
+    let mut parsed = Url::parse(url.trim()).map_err(|_e| RelayUrlError::Invalid(url.into()))?;
+    if parsed.scheme() != "ws" && parsed.scheme() != "wss" {
+        return Err(RelayUrlError::UnsupportedScheme(url.into()));
+    }
+    if parsed.host_str().map_or(true, str::is_empty) {
+        return Err(RelayUrlError::Invalid(url.into()));
+    }
+    parsed.set_fragment(None);
+    Ok(parsed.to_string())
+}
 
 pub struct RelayPoolTask {
     receiver: Receiver<RelayPoolEv>,
     notification_sender: broadcast::Sender<RelayPoolNotifications>,
-    events: HashMap<String, Event>,
+    //Not a notification, those are dropped when the receiver lags behind
+    disconnection_sender: Sender<String>,
+    events: EventCache,
+    sent: HashMap<String, Event>,
+    sent_order: VecDeque<String>,
 }
 
 impl RelayPoolTask {
     pub fn new(
         pool_task_receiver: Receiver<RelayPoolEv>,
         notification_sender: broadcast::Sender<RelayPoolNotifications>,
+        disconnection_sender: Sender<String>,
+        events: EventCache,
     ) -> Self {
         Self {
             receiver: pool_task_receiver,
-            events: HashMap::new(),
+            events,
             notification_sender,
+            disconnection_sender,
+            sent: HashMap::new(),
+            sent_order: VecDeque::new(),
+        }
+    }
+
+    fn add_sent(&mut self, ev: Event) {
+        let id = ev.id.to_string();
+        if self.sent.insert(id.clone(), ev).is_none() {
+            self.sent_order.push_back(id);
+        }
+        while self.sent_order.len() > MAX_SENT_EVENTS {
+            if let Some(oldest) = self.sent_order.pop_front() {
+                self.sent.remove(&oldest);
+            }
         }
     }
 
@@ -42,7 +149,7 @@ impl RelayPoolTask {
                         //Verifies if the event is valid
                         if let Ok(_) = event.verify() {
                             //Adds only new events
-                            if let None = self.events.insert(event.id.to_string(), event.clone()) {
+                            if self.events.insert(&event) {
                                 // TODO: set up optional logging
                                 debug!("New event, propagates");
                                 if let Err(e) = self
@@ -58,16 +165,70 @@ impl RelayPoolTask {
                 }
             }
             RelayPoolEv::EventSent { ev } => {
-                self.events.insert(ev.id.to_string(), ev.clone());
+                self.events.mark_sent(&ev);
+                self.add_sent(ev);
             }
+            RelayPoolEv::ReceivedAuth {
+                relay_url,
+                challenge,
+            } => {
+                if let Err(e) =
+                    self.notification_sender
+                        .send(RelayPoolNotifications::AuthChallenge {
+                            relay_url,
+                            challenge,
+                        })
+                {
+                    error!("RelayPoolNotifications::AuthChallenge error: {:?}", e);
+                }
+            }
+            RelayPoolEv::ReceivedOk {
+                relay_url,
+                event_id,
+                accepted,
+                message,
+            } => {
+                if !accepted {
+                    debug!("Event {} rejected by {}: {}", event_id, relay_url, message);
+                }
+                let ev = self.sent.get(&event_id).cloned();
+                if let Err(e) = self
+                    .notification_sender
+                    .send(RelayPoolNotifications::EventOk {
+                        relay_url,
+                        event_id,
+                        accepted,
+                        message,
+                        ev,
+                    })
+                {
+                    error!("RelayPoolNotifications::EventOk error: {:?}", e);
+                }
+            }
+            //Subscriptions closed until we authenticate are sent again by
+            //`RelayPool::handle_ok`
+            RelayPoolEv::ReceivedClosed {
+                relay_url,
+                subscription_id,
+                message,
+            } => debug!(
+                "Subscription {} closed by {}: {}",
+                subscription_id, relay_url, message
+            ),
             RelayPoolEv::RemoveContactEvents(contact_keys) => {
-                self.events.retain(|_, v| {
-                    v.pubkey != contact_keys.public_key
-                        && v.tags[0].content() != contact_keys.public_key.to_string()
-                });
+                self.events
+                    .remove_related(&contact_keys.public_key.to_string());
+            }
+            RelayPoolEv::RelayDisconnected { relay_url } => {
+                if let Err(e) = self.disconnection_sender.send(relay_url).await {
+                    error!("disconnection_sender error: {}", e);
+                }
+            }
+            RelayPoolEv::GetEventCacheMetrics(resp) => {
+                if resp.send(self.events.metrics()).is_err() {
+                    error!("RelayPoolEv::GetEventCacheMetrics response dropped");
+                }
             }
-
-            _ => (),
         }
     }
 }
@@ -84,30 +245,187 @@ pub struct RelayPool {
     subscription: Subscription,
     notification_receiver: broadcast::Receiver<RelayPoolNotifications>,
     notification_sender: broadcast::Sender<RelayPoolNotifications>,
+    //Connections opened in the background by `connect_all`
+    connection_sender: Sender<RelayConnection>,
+    connection_receiver: Option<Receiver<RelayConnection>>,
+    //Urls of the relays whose connection closed
+    disconnection_receiver: Option<Receiver<String>>,
+    //Relays outside the pool `send_ev_to` sends to, by url. Their
+    //connections close once idle for `TEMP_RELAY_TIMEOUT`
+    temp_relays: Mutex<HashMap<String, TempRelay>>,
+    //SOCKS5 proxy for relays without one of their own
+    proxy: Option<String>,
+    //NIP-13 difficulty for relays without one of their own
+    pow_difficulty: Option<u8>,
 }
 
 impl RelayPool {
-    pub fn new() -> Self {
+    //Events older than the last `event_cache_size` are looked up in `store`
+    pub fn new(
+        event_cache_size: usize,
+        store: Option<Arc<dyn EventStore>>,
+        proxy: Option<String>,
+    ) -> Self {
         let (notification_sender, notification_receiver) = broadcast::channel(64);
         let (sender, receiver) = mpsc::channel(64);
-        let relay_pool_task = RelayPoolTask::new(receiver, notification_sender.clone());
+        let (disconnection_sender, disconnection_receiver) = mpsc::channel(64);
+        let relay_pool_task = RelayPoolTask::new(
+            receiver,
+            notification_sender.clone(),
+            disconnection_sender,
+            EventCache::new(event_cache_size, store),
+        );
         tokio::spawn(start_relay_pool_task(relay_pool_task));
+        let (connection_sender, connection_receiver) = mpsc::channel(64);
         Self {
             relays: HashMap::new(),
             pool_task_sender: sender,
             subscription: Subscription::new(),
             notification_receiver,
             notification_sender,
+            connection_sender,
+            connection_receiver: Some(connection_receiver),
+            disconnection_receiver: Some(disconnection_receiver),
+            temp_relays: Mutex::new(HashMap::new()),
+            proxy,
+            pow_difficulty: None,
         }
     }
+
+    //Whoever takes it passes the connections to `handle_connection`
+    pub fn take_connection_ch(&mut self) -> Option<Receiver<RelayConnection>> {
+        self.connection_receiver.take()
+    }
+    //Whoever takes it passes the urls to `handle_disconnection`
+    pub fn take_disconnection_ch(&mut self) -> Option<Receiver<String>> {
+        self.disconnection_receiver.take()
+    }
     pub fn get_notifications_ch(&self) -> broadcast::Receiver<RelayPoolNotifications> {
         self.notification_sender.subscribe()
     }
-    pub fn add(&mut self, relay_url: &str) {
-        self.relays.insert(
-            relay_url.clone().into(),
-            Relay::new(relay_url, self.pool_task_sender.clone()),
-        );
+    pub fn add(&mut self, relay_config: &RelayConfig) {
+        let mut relay = Relay::new(&relay_config.url, self.pool_task_sender.clone());
+        relay.set_roles(relay_config);
+        relay.proxy = relay_config.proxy.clone();
+        relay.pow_difficulty = relay_config.pow_difficulty;
+        //Its temporary connection, if any, is closed when dropped
+        self.temp_relays.lock().unwrap().remove(&relay_config.url);
+        self.relays.insert(relay_config.url.clone(), relay);
+    }
+
+    pub fn set_pow_difficulty(&mut self, difficulty: Option<u8>) {
+        self.pow_difficulty = difficulty;
+    }
+
+    pub fn set_relay_pow_difficulty(&mut self, url: &str, difficulty: Option<u8>) {
+        if let Some(relay) = self.relays.get_mut(url) {
+            relay.pow_difficulty = difficulty;
+        }
+    }
+
+    //Difficulty asked by the relay when it rejected an event, used from
+    //then on for this relay
+    pub fn set_required_pow(&mut self, url: &str, difficulty: u8) {
+        if let Some(relay) = self.relays.get_mut(url) {
+            relay.required_pow = relay.required_pow.max(difficulty);
+        }
+    }
+
+    //Difficulty an event of `kind` needs to be taken by every relay it goes
+    //to, `send_ev_to` ones included: the highest of our settings, the
+    //relays' NIP-11 minimums and the difficulties they asked for
+    pub fn pow_difficulty(&self, kind: u64, relay_urls: &[String]) -> u8 {
+        let default = self.pow_difficulty.unwrap_or(0);
+        let in_pool = relay_urls.iter().filter_map(|url| self.relays.get(url));
+        self.target_relays_for(kind)
+            .into_iter()
+            .chain(in_pool)
+            .map(|r| {
+                let min_pow = r
+                    .limitation
+                    .as_ref()
+                    .and_then(|l| l.min_pow_difficulty)
+                    .unwrap_or(0);
+                r.pow_difficulty
+                    .unwrap_or(default)
+                    .max(min_pow)
+                    .max(r.required_pow)
+            })
+            .fold(default, u8::max)
+            .min(MAX_POW_DIFFICULTY)
+    }
+
+    pub fn proxy_for(&self, url: &str) -> Option<String> {
+        self.relays
+            .get(url)
+            .and_then(|r| r.proxy.clone())
+            .or_else(|| self.proxy.clone())
+    }
+
+    //Connected relays going through the changed proxy are reconnected
+    pub async fn set_proxy(&mut self, proxy: Option<String>) {
+        if self.proxy == proxy {
+            return;
+        }
+        self.proxy = proxy;
+        let urls: Vec<String> = self
+            .relays
+            .values()
+            .filter(|r| r.proxy.is_none())
+            .map(|r| r.url.clone())
+            .collect();
+        for url in urls {
+            self.reconnect_relay(&url).await;
+        }
+    }
+
+    pub async fn update_proxy(&mut self, relay_config: &RelayConfig) {
+        if let Some(relay) = self.relays.get_mut(&relay_config.url) {
+            if relay.proxy == relay_config.proxy {
+                return;
+            }
+            relay.proxy = relay_config.proxy.clone();
+            self.reconnect_relay(&relay_config.url).await;
+        }
+    }
+
+    async fn reconnect_relay(&mut self, url: &str) {
+        let connected = self
+            .relays
+            .get(url)
+            .map_or(false, |r| matches!(r.status, RelayStatus::Connected));
+        if connected {
+            self.disconnect_relay(url).await;
+            self.connect_relay(url).await;
+        }
+    }
+
+    //Applies new roles to a relay, updating its subscription if connected
+    pub async fn update_roles(&mut self, relay_config: &RelayConfig) {
+        if let Some(relay) = self.relays.get_mut(&relay_config.url) {
+            if relay.read == relay_config.read
+                && relay.write == relay_config.write
+                && relay.dm == relay_config.dm
+            {
+                return;
+            }
+            relay.set_roles(relay_config);
+            self.subscribe_relay(&relay_config.url).await;
+        }
+    }
+
+    //Limits advertised in the relay's NIP-11 document, applied from the
+    //next subscription or event sent
+    pub fn set_limitation(&mut self, url: &str, limitation: Option<RelayLimitation>) {
+        if let Some(relay) = self.relays.get_mut(url) {
+            relay.limitation = limitation;
+        }
+    }
+
+    //Limits known after the relay was subscribed
+    pub async fn update_limitation(&mut self, url: &str, limitation: Option<RelayLimitation>) {
+        self.set_limitation(url, limitation);
+        self.subscribe_relay(url).await;
     }
 
     pub fn list_relays(&self) -> Vec<Relay> {
@@ -124,6 +442,19 @@ impl RelayPool {
             error!("remove_contact_events send error: {}", e.to_string())
         };
     }
+    pub async fn event_cache_metrics(&self) -> Option<EventCacheMetrics> {
+        let (resp, res_rx) = oneshot::channel();
+        if let Err(e) = self
+            .pool_task_sender
+            .send(RelayPoolEv::GetEventCacheMetrics(resp))
+            .await
+        {
+            error!("event_cache_metrics send error: {}", e);
+            return None;
+        }
+        res_rx.await.ok()
+    }
+
     pub async fn send_ev(&self, ev: Event) {
         //Send to pool task to save in all received events
         if let Err(e) = self
@@ -133,14 +464,129 @@ impl RelayPool {
         {
             error!("send_ev send error: {}", e.to_string())
         };
-        let relays_clone = self.relays.clone();
-        for (_k, v) in relays_clone.iter() {
-            v.send_relay_ev(RelayEv::SendMsg(ClientMessage::new_event(ev.clone())))
+        for relay in self.target_relays(&ev) {
+            if let Err(e) = relay.check_event(&ev) {
+                debug!("Not sending {} to {}: {}", ev.id, relay.url, e);
+                continue;
+            }
+            relay
+                .send_relay_ev(RelayEv::SendMsg(ClientMessage::new_event(ev.clone())))
                 .await;
         }
     }
 
-    pub async fn start_sub(&mut self, filters: Vec<SubscriptionFilter>) {
+    //Only to `url`, if connected
+    pub async fn send_ev_to_relay(&self, url: &str, ev: Event) {
+        if let Err(e) = self
+            .pool_task_sender
+            .send(RelayPoolEv::EventSent { ev: ev.clone() })
+            .await
+        {
+            error!("send_ev_to_relay send error: {}", e.to_string())
+        };
+        if let Some(relay) = self.relays.get(url) {
+            relay.send_msg(ClientMessage::new_event(ev)).await;
+        }
+    }
+
+    //DMs and deletions also go to DM relays, everything else only to write
+    //relays
+    fn target_relays(&self, ev: &Event) -> Vec<&Relay> {
+        self.target_relays_for(get_kind(ev))
+    }
+
+    fn target_relays_for(&self, kind: u64) -> Vec<&Relay> {
+        let is_dm = kind == KIND_ENCRYPTED_DM || kind == KIND_GIFT_WRAP || kind == KIND_DELETION;
+        self.relays
+            .values()
+            .filter(|r| r.write || (is_dm && r.dm))
+            .collect()
+    }
+
+    //Relays among the ones `send_ev_to` would use that can't take the event.
+    //Fails if none of them can. Limits of relays not in the pool are unknown
+    pub fn check_ev(
+        &self,
+        ev: &Event,
+        relay_urls: &[String],
+    ) -> Result<Vec<RelayRejection>, RelayPoolError> {
+        let targets = self.target_relays(ev);
+        let mut rejections = vec![];
+        let mut accepted = relay_urls
+            .iter()
+            .filter(|url| !self.relays.contains_key(*url))
+            .count();
+        let in_pool = relay_urls.iter().filter_map(|url| self.relays.get(url));
+        for relay in targets.into_iter().chain(in_pool) {
+            if rejections
+                .iter()
+                .any(|r: &RelayRejection| r.relay_url == relay.url)
+            {
+                continue;
+            }
+            match relay.check_event(ev) {
+                Ok(()) => accepted += 1,
+                Err(e) => rejections.push(RelayRejection {
+                    relay_url: relay.url.clone(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        if accepted == 0 && !rejections.is_empty() {
+            return Err(RelayPoolError::EventRejected(rejections));
+        }
+        Ok(rejections)
+    }
+
+    //Sends to our relays and to `relay_urls`, through temporary connections
+    //to the ones not in the pool. Events wait for the connection to open
+    pub async fn send_ev_to(&self, ev: Event, relay_urls: &[String]) {
+        self.send_ev(ev.clone()).await;
+        let mut connected = vec![];
+        {
+            let mut temp_relays = self.temp_relays.lock().unwrap();
+            for url in relay_urls {
+                if self.relays.contains_key(url) {
+                    continue;
+                }
+                if let Some(temp) = temp_relays.get_mut(url) {
+                    match temp.relay.status {
+                        RelayStatus::Connected => connected.push(temp.relay.clone()),
+                        _ if temp.queued.len() < MAX_PENDING_EVENTS => temp.queued.push(ev.clone()),
+                        _ => debug!("Too many events waiting for {}", url),
+                    }
+                    continue;
+                }
+                if temp_relays.len() >= MAX_TEMP_RELAYS {
+                    debug!(
+                        "Too many temporary relays, not sending {} to {}",
+                        ev.id, url
+                    );
+                    continue;
+                }
+                let mut relay = Relay::new(url, self.pool_task_sender.clone());
+                relay.status = RelayStatus::Connecting;
+                temp_relays.insert(
+                    url.clone(),
+                    TempRelay {
+                        relay,
+                        queued: vec![ev.clone()],
+                    },
+                );
+                self.spawn_open(
+                    url,
+                    self.proxy.clone(),
+                    Some(TEMP_RELAY_TIMEOUT),
+                    Duration::from_secs(0),
+                );
+            }
+        }
+        for relay in connected {
+            relay.send_msg(ClientMessage::new_event(ev.clone())).await;
+        }
+    }
+
+    pub async fn start_sub(&mut self, filters: Vec<Filter>) {
         self.subscription.update_filters(filters.clone());
         let relays_clone = self.relays.clone();
         for (k, _) in relays_clone.iter() {
@@ -152,13 +598,31 @@ impl RelayPool {
         if let Some(relay) = self.relays.get(url) {
             match relay.status {
                 RelayStatus::Connected => {
-                    let channel = self.subscription.get_channel(url);
-                    relay
-                        .send_msg(nostr::ClientMessage::new_req(
-                            channel.id.clone(),
-                            self.subscription.get_filters(),
-                        ))
-                        .await;
+                    //Read relays get every filter, DM relays only DM filters
+                    let filters: Vec<Filter> = if relay.read {
+                        self.subscription.get_filters()
+                    } else if relay.dm {
+                        self.subscription
+                            .get_filters()
+                            .into_iter()
+                            .filter(Filter::is_dm)
+                            .collect()
+                    } else {
+                        vec![]
+                    };
+                    if filters.is_empty() {
+                        return self.unsubscribe_relay(url).await;
+                    }
+                    //Only the changes since the last subscription are sent
+                    let diff =
+                        self.subscription
+                            .update_channels(url, filters, relay.limitation.as_ref());
+                    for ch in diff.close {
+                        relay.send_msg(nostr::ClientMessage::close(ch.id)).await;
+                    }
+                    for ch in diff.open {
+                        relay.send_req(&ch.id, &ch.filters).await;
+                    }
                 }
                 _ => (),
             }
@@ -168,7 +632,7 @@ impl RelayPool {
         if let Some(relay) = self.relays.get(url) {
             match relay.status {
                 RelayStatus::Connected => {
-                    if let Some(ch) = self.subscription.remove_channel(url) {
+                    for ch in self.subscription.remove_channels(url) {
                         relay.send_msg(nostr::ClientMessage::close(ch.id)).await;
                     }
                 }
@@ -177,26 +641,246 @@ impl RelayPool {
         }
     }
 
-    pub async fn connect_all(&mut self) {
-        for (relay_url, _) in self.relays.clone().iter() {
-            self.connect_relay(relay_url).await
+    //Relays are connected concurrently without waiting for them, each one
+    //is subscribed by `handle_connection` as it comes up
+    pub fn connect_all(&mut self) {
+        let urls: Vec<String> = self
+            .relays
+            .values()
+            .filter(|r| matches!(r.status, RelayStatus::Disconnected))
+            .map(|r| r.url.clone())
+            .collect();
+        for url in urls {
+            self.spawn_connect(&url, Duration::from_secs(0));
         }
     }
-    pub async fn connect_relay(&mut self, url: &str) {
-        self.relays
-            .get_mut::<String>(&String::from(url))
-            .unwrap()
-            .connect()
-            .await;
+
+    fn spawn_connect(&mut self, url: &str, delay: Duration) {
+        let proxy = self.proxy_for(url);
+        let relay = match self.relays.get_mut(url) {
+            Some(relay) => relay,
+            None => return,
+        };
+        relay.status = RelayStatus::Connecting;
+        self.spawn_open(url, proxy, None, delay);
+    }
+
+    //The connection is passed to `handle_connection`
+    fn spawn_open(
+        &self,
+        url: &str,
+        proxy: Option<String>,
+        idle_timeout: Option<Duration>,
+        delay: Duration,
+    ) {
+        let relay_url = url.to_string();
+        let pool_sender = self.pool_task_sender.clone();
+        let connection_sender = self.connection_sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let relay_sender = Relay::open(&relay_url, proxy, idle_timeout, pool_sender).await;
+            let connection = RelayConnection {
+                relay_url,
+                relay_sender,
+            };
+            if let Err(e) = connection_sender.send(connection).await {
+                error!("connection_sender error: {}", e);
+            }
+        });
+    }
+
+    //Failed connections are tried again, waiting longer after each attempt
+    fn schedule_reconnect(&mut self, url: &str) {
+        let attempts = match self.relays.get_mut(url) {
+            Some(relay) => {
+                relay.reconnect_attempts += 1;
+                relay.reconnect_attempts
+            }
+            None => return,
+        };
+        let delay = RECONNECT_DELAY
+            .saturating_mul(2u32.saturating_pow(attempts - 1))
+            .min(MAX_RECONNECT_DELAY);
+        debug!("Reconnecting to {} in {}s", url, delay.as_secs());
+        self.spawn_connect(url, delay);
+    }
+
+    //Connections lost, not closed by us, are opened again. Temporary ones
+    //are forgotten
+    pub fn handle_disconnection(&mut self, url: &str) {
+        if !self.relays.contains_key(url) {
+            let mut temp_relays = self.temp_relays.lock().unwrap();
+            let closed = temp_relays.get(url).map_or(false, |t| {
+                t.relay
+                    .relay_sender
+                    .as_ref()
+                    .map_or(false, |s| s.is_closed())
+            });
+            if closed {
+                temp_relays.remove(url);
+            }
+            return;
+        }
+        let lost = match self.relays.get(url) {
+            //A newer connection may be up already
+            Some(relay) => {
+                matches!(relay.status, RelayStatus::Connected)
+                    && relay.relay_sender.as_ref().map_or(true, |s| s.is_closed())
+            }
+            None => false,
+        };
+        if lost {
+            error!("Lost connection to relay {}", url);
+            if let Some(relay) = self.relays.get_mut(url) {
+                relay.status = RelayStatus::Disconnected;
+                relay.relay_sender = None;
+            }
+            self.subscription.remove_channels(url);
+            self.schedule_reconnect(url);
+        }
+    }
+
+    pub async fn handle_connection(&mut self, connection: RelayConnection) {
+        let RelayConnection {
+            relay_url,
+            relay_sender,
+        } = connection;
+        if !self.relays.contains_key(&relay_url) {
+            return self.handle_temp_connection(&relay_url, relay_sender).await;
+        }
+        match self.relays.get_mut(&relay_url) {
+            //Removed or disconnected while connecting
+            Some(relay) if !matches!(relay.status, RelayStatus::Connecting) => {
+                close_connection(relay_sender).await
+            }
+            None => close_connection(relay_sender).await,
+            Some(relay) => match relay_sender {
+                Some(relay_sender) => {
+                    relay.status = RelayStatus::Connected;
+                    relay.relay_sender = Some(relay_sender);
+                    relay.reconnect_attempts = 0;
+                    relay.auth = AuthStatus::None;
+                    self.subscription.remove_channels(&relay_url);
+                    self.subscribe_relay(&relay_url).await;
+                }
+                None => {
+                    relay.status = RelayStatus::Disconnected;
+                    self.schedule_reconnect(&relay_url);
+                }
+            },
+        }
+    }
+    //Queued events are sent once connected
+    async fn handle_temp_connection(&mut self, url: &str, relay_sender: Option<Sender<RelayEv>>) {
+        let connected = {
+            let mut temp_relays = self.temp_relays.lock().unwrap();
+            match (temp_relays.get_mut(url), relay_sender) {
+                (Some(temp), Some(relay_sender))
+                    if matches!(temp.relay.status, RelayStatus::Connecting) =>
+                {
+                    temp.relay.status = RelayStatus::Connected;
+                    temp.relay.relay_sender = Some(relay_sender);
+                    Ok((temp.relay.clone(), std::mem::take(&mut temp.queued)))
+                }
+                (Some(_), None) => {
+                    debug!("Can't send events to {}", url);
+                    temp_relays.remove(url);
+                    return;
+                }
+                //Closed or added to the pool while connecting
+                (_, relay_sender) => Err(relay_sender),
+            }
+        };
+        match connected {
+            Ok((relay, queued)) => {
+                for ev in queued {
+                    relay.send_msg(ClientMessage::new_event(ev)).await;
+                }
+            }
+            Err(relay_sender) => close_connection(relay_sender).await,
+        }
+    }
+
+    //NIP-42, the relay serves us once it accepts `ev`. Temporary relays
+    //authenticate the same way
+    pub async fn send_auth(&mut self, url: &str, ev: Event) {
+        let auth_id = ev.id.to_string();
+        let relay = match self.relays.get_mut(url) {
+            Some(relay) => {
+                relay.auth = AuthStatus::Pending(auth_id);
+                relay.clone()
+            }
+            None => match self.temp_relays.lock().unwrap().get_mut(url) {
+                Some(temp) => {
+                    temp.relay.auth = AuthStatus::Pending(auth_id);
+                    temp.relay.clone()
+                }
+                None => return,
+            },
+        };
+        relay.send_relay_ev(RelayEv::SendText(auth_msg(&ev))).await;
+    }
+
+    //Once authenticated, subscriptions and events the relay refused are
+    //sent again
+    pub async fn handle_ok(
+        &mut self,
+        url: &str,
+        event_id: &str,
+        accepted: bool,
+        message: &str,
+        ev: Option<Event>,
+    ) {
+        let (relay, pending) = match self.relays.get_mut(url) {
+            Some(relay) => match relay.handle_ok(event_id, accepted, message, ev) {
+                Some(pending) => (relay.clone(), pending),
+                None => return,
+            },
+            None => {
+                let mut temp_relays = self.temp_relays.lock().unwrap();
+                let temp = match temp_relays.get_mut(url) {
+                    Some(temp) => temp,
+                    None => return,
+                };
+                match temp.relay.handle_ok(event_id, accepted, message, ev) {
+                    Some(pending) => (temp.relay.clone(), pending),
+                    None => return,
+                }
+            }
+        };
+        for ev in pending {
+            relay.send_msg(ClientMessage::new_event(ev)).await;
+        }
+        self.unsubscribe_relay(url).await;
         self.subscribe_relay(url).await;
     }
+
+    pub async fn connect_relay(&mut self, url: &str) {
+        let proxy = self.proxy_for(url);
+        if let Some(relay) = self.relays.get_mut(url) {
+            //Subscriptions don't survive the connection
+            self.subscription.remove_channels(url);
+            relay.connect(proxy).await;
+            self.subscribe_relay(url).await;
+        }
+    }
     pub async fn disconnect_relay(&mut self, url: &str) {
         self.unsubscribe_relay(url).await;
-        self.relays
-            .get_mut::<String>(&String::from(url))
-            .unwrap()
-            .disconnect()
-            .await;
+        if let Some(relay) = self.relays.get_mut(url) {
+            relay.disconnect().await;
+        }
+    }
+    //Closes the subscription and the connection before dropping the relay
+    pub async fn remove(&mut self, url: &str) {
+        self.disconnect_relay(url).await;
+        self.subscription.remove_channels(url);
+        self.relays.remove(url);
+    }
+    pub fn contains(&self, url: &str) -> bool {
+        self.relays.contains_key(url)
+    }
+    pub fn is_temp_relay(&self, url: &str) -> bool {
+        self.temp_relays.lock().unwrap().contains_key(url)
     }
 }
 
@@ -204,6 +888,18 @@ impl RelayPool {
 pub struct Relay {
     pub url: String,
     pub status: RelayStatus,
+    pub read: bool,
+    pub write: bool,
+    pub dm: bool,
+    pub limitation: Option<RelayLimitation>,
+    pub proxy: Option<String>,
+    pub pow_difficulty: Option<u8>,
+    //Difficulty the relay asked for in its last rejection
+    pub required_pow: u8,
+    pub auth: AuthStatus,
+    //Events refused until we authenticate
+    pending_events: VecDeque<Event>,
+    reconnect_attempts: u32,
     pool_sender: Sender<RelayPoolEv>,
     relay_sender: Option<Sender<RelayEv>>,
 }
@@ -213,92 +909,283 @@ impl Relay {
         Self {
             url: url.into(),
             status: RelayStatus::Disconnected,
+            read: true,
+            write: true,
+            dm: true,
+            limitation: None,
+            proxy: None,
+            pow_difficulty: None,
+            required_pow: 0,
+            auth: AuthStatus::None,
+            pending_events: VecDeque::new(),
+            reconnect_attempts: 0,
             pool_sender,
             relay_sender: None,
         }
     }
 
-    pub async fn connect(&mut self) {
-        let url = url::Url::parse(&self.url).unwrap();
+    pub fn set_roles(&mut self, relay_config: &RelayConfig) {
+        self.read = relay_config.read;
+        self.write = relay_config.write;
+        self.dm = relay_config.dm;
+    }
+
+    pub fn check_event(&self, ev: &Event) -> Result<(), LimitationError> {
+        match &self.limitation {
+            Some(limitation) => limitation.check_event(ev),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn connect(&mut self, proxy: Option<String>) {
+        match Self::open(&self.url, proxy, None, self.pool_sender.clone()).await {
+            Some(relay_sender) => {
+                self.relay_sender = Some(relay_sender);
+                self.status = RelayStatus::Connected;
+                self.auth = AuthStatus::None;
+            }
+            None => self.status = RelayStatus::Disconnected,
+        }
+    }
+
+    //Opens the websocket and spawns its writer and reader tasks. Returns the
+    //channel to the writer, None if the relay didn't answer in time. With
+    //`idle_timeout` the connection closes when nothing is sent for that long
+    async fn open(
+        relay_url: &str,
+        proxy: Option<String>,
+        idle_timeout: Option<Duration>,
+        pool_sender: Sender<RelayPoolEv>,
+    ) -> Option<Sender<RelayEv>> {
+        let url = match Url::parse(relay_url) {
+            Ok(url) => url,
+            Err(e) => {
+                error!("Invalid relay URL {}: {}", relay_url, e);
+                return None;
+            }
+        };
         debug!("Trying to connect {} ...", url.to_string());
 
-        //TODO: Maybe propagate errors
-        if let Ok((ws_stream, _)) = connect_async(&url).await {
-            debug!("Successfully connected to relay {}!", &url.to_string());
-            self.status = RelayStatus::Connected;
+        let timeout = match proxy {
+            Some(_) => PROXY_CONNECT_TIMEOUT,
+            None => CONNECT_TIMEOUT,
+        };
+        let connecting = async {
+            match &proxy {
+                Some(proxy) => {
+                    let host = url.host_str().unwrap_or_default();
+                    let port = url.port_or_known_default().unwrap_or(80);
+                    let stream = socks::connect(proxy, host, port)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    client_async_tls(&url, stream)
+                        .await
+                        .map_err(|e| e.to_string())
+                }
+                //Onion services are only reachable through Tor
+                None if url.host_str().map_or(false, |h| h.ends_with(".onion")) => {
+                    Err("no proxy configured for .onion relay".to_string())
+                }
+                None => connect_async(&url).await.map_err(|e| e.to_string()),
+            }
+        };
+        let ws_stream = match tokio::time::timeout(timeout, connecting).await {
+            Ok(Ok((ws_stream, _))) => ws_stream,
+            Ok(Err(e)) => {
+                debug!("Can't connect to relay {}: {}", url, e);
+                return None;
+            }
+            Err(_) => {
+                debug!("Connecting to relay {} timed out", url);
+                return None;
+            }
+        };
+        debug!("Successfully connected to relay {}!", &url.to_string());
+
+        let (mut ws_tx, mut ws_rx) = ws_stream.split();
+        let relay_url = url.to_string();
+        //Any frame received shows the connection is alive
+        let last_seen = Arc::new(AtomicI64::new(
+            chrono::offset::Utc::now().timestamp_millis(),
+        ));
 
-            let (mut ws_tx, mut ws_rx) = ws_stream.split();
+        let reader_last_seen = last_seen.clone();
+        let reader_pool_sender = pool_sender.clone();
+        let reader_url = relay_url.clone();
+        let mut reader = tokio::spawn(async move {
+            while let Some(msg_res) = ws_rx.next().await {
+                reader_last_seen.store(
+                    chrono::offset::Utc::now().timestamp_millis(),
+                    Ordering::Relaxed,
+                );
+                let text = match msg_res {
+                    Ok(Message::Text(text)) => text,
+                    //Relays should only send text, binary frames are read if
+                    //they are UTF-8
+                    Ok(Message::Binary(data)) => match String::from_utf8(data) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            debug!("Non UTF-8 binary frame from {}: {}", reader_url, e);
+                            continue;
+                        }
+                    },
+                    //Pings are answered by tungstenite on the next write
+                    Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => continue,
+                    Ok(Message::Close(frame)) => {
+                        debug!("Relay {} closed the connection: {:?}", reader_url, frame);
+                        break;
+                    }
+                    Err(err) => {
+                        error!("{}", err);
+                        break;
+                    }
+                };
+                if let Some(ev) = parse_extra_msg(&reader_url, &text) {
+                    if let Err(err) = reader_pool_sender.send(ev).await {
+                        debug!("[CH Relay -> RelayPool] {}", &err);
+                    }
+                    continue;
+                }
+                match nostr::RelayMessage::from_json(&text) {
+                    Ok(msg) => {
+                        match reader_pool_sender
+                            .send(RelayPoolEv::ReceivedMsg {
+                                relay_url: reader_url.clone(),
+                                msg,
+                            })
+                            .await
+                        {
+                            Ok(_) => {
+                                debug!("[CH Relay -> RelayPool] Sent to relay pool");
+                            }
+                            Err(err) => {
+                                debug!("[CH Relay -> RelayPool] {}", &err);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("{}", err);
+                    }
+                }
+            }
+            debug!("Closed WS RX to RELAY POOL TX {}", reader_url);
+        });
 
-            let (relay_sender, mut relay_receiver) = mpsc::channel::<RelayEv>(32);
-            self.relay_sender = Some(relay_sender);
-            let url_clone = url.clone().to_string();
-            tokio::spawn(async move {
-                while let Some(relay_ev) = relay_receiver.recv().await {
-                    match relay_ev {
-                        RelayEv::SendMsg(msg) => {
+        let (relay_sender, mut relay_receiver) = mpsc::channel::<RelayEv>(32);
+        tokio::spawn(async move {
+            let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+            //When the unanswered ping was sent and when it times out
+            let mut pong_deadline: Option<(i64, tokio::time::Instant)> = None;
+            let mut idle_deadline = idle_timeout.map(|t| tokio::time::Instant::now() + t);
+            loop {
+                tokio::select! {
+                    relay_ev = relay_receiver.recv() => match relay_ev {
+                        Some(RelayEv::SendMsg(msg)) => {
+                            idle_deadline = idle_timeout.map(|t| tokio::time::Instant::now() + t);
                             println!("Sending message {}", msg.to_json());
                             if let Err(e) = ws_tx.send(Message::Text(msg.to_json())).await {
                                 error!("RelayEv::SendMsg error: {:?}", e);
                             };
                         }
-                        RelayEv::Close => {
+                        Some(RelayEv::SendText(msg)) => {
+                            idle_deadline = idle_timeout.map(|t| tokio::time::Instant::now() + t);
+                            debug!("Sending message {}", msg);
+                            if let Err(e) = ws_tx.send(Message::Text(msg)).await {
+                                error!("RelayEv::SendText error: {:?}", e);
+                            };
+                        }
+                        Some(RelayEv::Close) | None => {
                             if let Err(e) = ws_tx.close().await {
                                 error!("RelayEv::Close error: {:?}", e);
                             };
-                            relay_receiver.close();
+                            reader.abort();
+                            break;
+                        }
+                    },
+                    _ = tokio::time::sleep_until(
+                        idle_deadline.unwrap_or_else(tokio::time::Instant::now)
+                    ), if idle_deadline.is_some() => {
+                        debug!("Closing idle connection to {}", relay_url);
+                        if let Err(e) = ws_tx.close().await {
+                            error!("Idle close error: {:?}", e);
+                        };
+                        reader.abort();
+                        break;
+                    }
+                    _ = ping_interval.tick() => {
+                        let sent_at = chrono::offset::Utc::now().timestamp_millis();
+                        if let Err(e) = ws_tx.send(Message::Ping(vec![])).await {
+                            error!("Ping error: {:?}", e);
+                        } else if pong_deadline.is_none() {
+                            pong_deadline =
+                                Some((sent_at, tokio::time::Instant::now() + PONG_TIMEOUT));
                         }
                     }
+                    _ = tokio::time::sleep_until(
+                        pong_deadline.map_or_else(tokio::time::Instant::now, |(_, d)| d)
+                    ), if pong_deadline.is_some() => {
+                        let (sent_at, _) = pong_deadline.take().unwrap();
+                        if last_seen.load(Ordering::Relaxed) < sent_at {
+                            error!(
+                                "Relay {} didn't answer a ping within {}s",
+                                relay_url,
+                                PONG_TIMEOUT.as_secs()
+                            );
+                            reader.abort();
+                            break;
+                        }
+                    }
+                    _ = &mut reader => break,
                 }
-                debug!("Closed RELAY TX to WS RX {}", url_clone);
-            });
+            }
+            //The channel is closed before the pool hears about it, so it can
+            //tell this connection from a newer one
+            drop(relay_receiver);
+            if let Err(e) = pool_sender
+                .send(RelayPoolEv::RelayDisconnected {
+                    relay_url: relay_url.clone(),
+                })
+                .await
+            {
+                error!("pool_send error: {}", e.to_string())
+            };
+            debug!("Closed RELAY TX to WS RX {}", relay_url);
+        });
+        Some(relay_sender)
+    }
 
-            let pool_sender = self.pool_sender.clone();
-            tokio::spawn(async move {
-                let relay_url = url.to_string();
-                while let Some(msg_res) = ws_rx.next().await {
-                    match msg_res {
-                        Ok(msg) => {
-                            let data = msg.into_data();
-                            let data_to_str = String::from_utf8(data).unwrap();
-                            //   println!("Received data {}", &data_to_str);
-                            match nostr::RelayMessage::from_json(&data_to_str) {
-                                Ok(msg) => {
-                                    //                               println!("[Received RelayMsg]: {}", &msg.to_json());
-                                    match pool_sender
-                                        .send(RelayPoolEv::ReceivedMsg {
-                                            relay_url: relay_url.clone(),
-                                            msg,
-                                        })
-                                        .await
-                                    {
-                                        Ok(_) => {
-                                            debug!("[CH Relay -> RelayPool] Sent to relay pool");
-                                        }
-                                        Err(err) => {
-                                            debug!("[CH Relay -> RelayPool] {}", &err);
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    error!("{}", err);
-                                }
-                            }
-                        }
-                        Err(err) => {
-                            error!("{}", err);
+    //Returns the events to send again once authenticated
+    fn handle_ok(
+        &mut self,
+        event_id: &str,
+        accepted: bool,
+        message: &str,
+        ev: Option<Event>,
+    ) -> Option<Vec<Event>> {
+        match &self.auth {
+            AuthStatus::Pending(auth_id) if auth_id == event_id => {
+                if !accepted {
+                    error!("Authentication to {} failed: {}", self.url, message);
+                    self.auth = AuthStatus::None;
+                    return None;
+                }
+                debug!("Authenticated to {}", self.url);
+                self.auth = AuthStatus::Authenticated;
+                Some(self.pending_events.drain(..).collect())
+            }
+            //Rejected again once authenticated, not retried
+            AuthStatus::Authenticated => None,
+            _ => {
+                if let (false, Some(ev)) = (accepted, ev) {
+                    if is_auth_required(message) {
+                        self.pending_events.push_back(ev);
+                        if self.pending_events.len() > MAX_PENDING_EVENTS {
+                            self.pending_events.pop_front();
                         }
                     }
                 }
-                if let Err(e) = pool_sender
-                    .send(RelayPoolEv::RelayDisconnected {
-                        relay_url: relay_url.clone(),
-                    })
-                    .await
-                {
-                    error!("pool_send error: {}", e.to_string())
-                };
-                error!("Closed WS RX to RELAY POOL TX {}", relay_url);
-            });
+                None
+            }
         }
     }
 
@@ -311,6 +1198,19 @@ impl Relay {
         self.send_relay_ev(RelayEv::SendMsg(msg)).await;
     }
 
+    //["REQ", <subscription id>, <filter>...]
+    pub async fn send_req(&self, subscription_id: &str, filters: &[Filter]) {
+        let mut req = vec![serde_json::json!("REQ"), serde_json::json!(subscription_id)];
+        for filter in filters {
+            match serde_json::to_value(filter) {
+                Ok(filter) => req.push(filter),
+                Err(e) => error!("Filter serialization error: {}", e),
+            }
+        }
+        let req = serde_json::Value::Array(req).to_string();
+        self.send_relay_ev(RelayEv::SendText(req)).await;
+    }
+
     async fn send_relay_ev(&self, relay_msg: RelayEv) {
         if self.relay_sender.is_some() {
             if let Err(e) = self.relay_sender.clone().unwrap().send(relay_msg).await {
@@ -320,11 +1220,77 @@ impl Relay {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthStatus {
+    None,
+    //Waiting for the relay to accept our auth event
+    Pending(String),
+    Authenticated,
+}
+
 #[derive(Debug, Clone)]
 pub enum RelayStatus {
     Disconnected,
     Connected,
-    _Connecting,
+    Connecting,
+}
+
+//Relay outside the pool and the events waiting for its connection
+struct TempRelay {
+    relay: Relay,
+    queued: Vec<Event>,
+}
+
+//Result of a connection attempt made by `RelayPool::connect_all`
+#[derive(Debug)]
+pub struct RelayConnection {
+    relay_url: String,
+    relay_sender: Option<Sender<RelayEv>>,
+}
+
+//["AUTH", <event>], nostr-rs only frames events as EVENT messages
+fn auth_msg(ev: &Event) -> String {
+    serde_json::json!(["AUTH", ev]).to_string()
+}
+
+//Relay messages nostr-rs doesn't know about
+fn parse_extra_msg(relay_url: &str, text: &str) -> Option<RelayPoolEv> {
+    let head = text.trim_start_matches(|c: char| c == '[' || c.is_whitespace());
+    if !["\"AUTH\"", "\"OK\"", "\"CLOSED\""]
+        .iter()
+        .any(|name| head.starts_with(name))
+    {
+        return None;
+    }
+    let msg: Vec<serde_json::Value> = serde_json::from_str(text).ok()?;
+    let relay_url = relay_url.to_string();
+    let text_at = |i: usize| msg.get(i).and_then(|v| v.as_str()).map(String::from);
+    match msg.first()?.as_str()? {
+        "AUTH" => Some(RelayPoolEv::ReceivedAuth {
+            relay_url,
+            challenge: text_at(1)?,
+        }),
+        "OK" => Some(RelayPoolEv::ReceivedOk {
+            relay_url,
+            event_id: text_at(1)?,
+            accepted: msg.get(2)?.as_bool()?,
+            message: text_at(3).unwrap_or_default(),
+        }),
+        "CLOSED" => Some(RelayPoolEv::ReceivedClosed {
+            relay_url,
+            subscription_id: text_at(1)?,
+            message: text_at(2).unwrap_or_default(),
+        }),
+        _ => None,
+    }
+}
+
+async fn close_connection(relay_sender: Option<Sender<RelayEv>>) {
+    if let Some(relay_sender) = relay_sender {
+        if let Err(e) = relay_sender.send(RelayEv::Close).await {
+            error!("close_connection error: {}", e);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -337,18 +1303,138 @@ pub enum RelayPoolEv {
         msg: nostr::RelayMessage,
     },
     RemoveContactEvents(Keys),
+    GetEventCacheMetrics(oneshot::Sender<EventCacheMetrics>),
     EventSent {
         ev: Event,
     },
+    ReceivedAuth {
+        relay_url: String,
+        challenge: String,
+    },
+    ReceivedOk {
+        relay_url: String,
+        event_id: String,
+        accepted: bool,
+        message: String,
+    },
+    ReceivedClosed {
+        relay_url: String,
+        subscription_id: String,
+        message: String,
+    },
 }
 #[derive(Debug, Clone)]
 pub enum RelayPoolNotifications {
-    ReceivedEvent { ev: Event },
-    _RelaysStatusChanged { relays: Vec<Relay> },
+    ReceivedEvent {
+        ev: Event,
+    },
+    _RelaysStatusChanged {
+        relays: Vec<Relay>,
+    },
+    AuthChallenge {
+        relay_url: String,
+        challenge: String,
+    },
+    //`ev` is the event if we sent it lately
+    EventOk {
+        relay_url: String,
+        event_id: String,
+        accepted: bool,
+        message: String,
+        ev: Option<Event>,
+    },
 }
 
 #[derive(Debug)]
 enum RelayEv {
     SendMsg(nostr::ClientMessage),
+    SendText(String),
     Close,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        event::new_signed_event_at,
+        nip42::{new_auth_event, KIND_AUTH},
+    };
+
+    const RELAY_URL: &str = "wss://relay.example.com";
+
+    #[test]
+    fn parses_auth_challenge() {
+        let ev = parse_extra_msg(RELAY_URL, r#"["AUTH", "challenge-string"]"#);
+        assert!(matches!(
+            ev,
+            Some(RelayPoolEv::ReceivedAuth { relay_url, challenge })
+                if relay_url == RELAY_URL && challenge == "challenge-string"
+        ));
+    }
+
+    #[test]
+    fn parses_ok_and_closed() {
+        let ev = parse_extra_msg(RELAY_URL, r#"["OK","abcd",false,"auth-required: sign in"]"#);
+        assert!(matches!(
+            ev,
+            Some(RelayPoolEv::ReceivedOk { event_id, accepted: false, message, .. })
+                if event_id == "abcd" && message == "auth-required: sign in"
+        ));
+        let ev = parse_extra_msg(RELAY_URL, r#"["OK","abcd",true]"#);
+        assert!(matches!(
+            ev,
+            Some(RelayPoolEv::ReceivedOk { accepted: true, message, .. }) if message.is_empty()
+        ));
+        let ev = parse_extra_msg(RELAY_URL, r#"["CLOSED","sub1","auth-required: dms"]"#);
+        assert!(matches!(
+            ev,
+            Some(RelayPoolEv::ReceivedClosed { subscription_id, message, .. })
+                if subscription_id == "sub1" && message == "auth-required: dms"
+        ));
+    }
+
+    #[test]
+    fn ignores_other_and_malformed_msgs() {
+        assert!(parse_extra_msg(RELAY_URL, r#"["NOTICE","hello"]"#).is_none());
+        assert!(parse_extra_msg(RELAY_URL, r#"["EOSE","sub1"]"#).is_none());
+        assert!(parse_extra_msg(RELAY_URL, r#"["OK","abcd"]"#).is_none());
+        assert!(parse_extra_msg(RELAY_URL, r#"["AUTH"]"#).is_none());
+        assert!(parse_extra_msg(RELAY_URL, r#"["AUTH", "x""#).is_none());
+    }
+
+    #[test]
+    fn frames_auth_event_as_auth_msg() {
+        let keys = Keys::generate_from_os_random().unwrap();
+        let ev = new_auth_event(&keys, RELAY_URL, "challenge-string").unwrap();
+        let msg: serde_json::Value = serde_json::from_str(&auth_msg(&ev)).unwrap();
+        let msg = msg.as_array().unwrap();
+        assert_eq!(msg.len(), 2);
+        assert_eq!(msg[0], "AUTH");
+        assert_eq!(msg[1]["id"], ev.id.to_string());
+        assert_eq!(msg[1]["kind"], KIND_AUTH);
+        assert_eq!(msg[1]["tags"][1][1], "challenge-string");
+    }
+
+    #[tokio::test]
+    async fn caps_temporary_relays() {
+        let mut pool = RelayPool::new(10, None, None);
+        let keys = Keys::generate_from_os_random().unwrap();
+        let ev = new_signed_event_at(&keys, 1_700_000_000, 1, vec![], "hello").unwrap();
+        let urls: Vec<String> = (0..MAX_TEMP_RELAYS + 5)
+            .map(|i| format!("ws://127.0.0.1:1/{}", i))
+            .collect();
+        pool.send_ev_to(ev.clone(), &urls).await;
+        assert_eq!(pool.temp_relays.lock().unwrap().len(), MAX_TEMP_RELAYS);
+        assert!(pool.is_temp_relay(&urls[0]));
+
+        //Connections are reused, events wait for them to open
+        pool.send_ev_to(ev, &urls[..1]).await;
+        assert_eq!(pool.temp_relays.lock().unwrap()[&urls[0]].queued.len(), 2);
+
+        //Relays that can't be reached are forgotten
+        let mut connections = pool.take_connection_ch().unwrap();
+        let connection = connections.recv().await.unwrap();
+        pool.handle_connection(connection).await;
+        assert_eq!(pool.temp_relays.lock().unwrap().len(), MAX_TEMP_RELAYS - 1);
+    }
+}
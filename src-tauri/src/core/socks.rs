@@ -0,0 +1,254 @@
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+//SOCKS5 client, enough to reach relays through a local Tor daemon
+//https://www.rfc-editor.org/rfc/rfc1928
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+#[derive(Debug, Error)]
+pub enum SocksError {
+    #[error("Invalid proxy address: `{0}`")]
+    InvalidProxy(String),
+    #[error("Proxy connection failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Proxy requires authentication")]
+    AuthRequired,
+    #[error("Invalid proxy reply")]
+    InvalidReply,
+    #[error("Proxy refused the connection: {0}")]
+    ConnectFailed(&'static str),
+    #[error("Host name too long: `{0}`")]
+    HostTooLong(String),
+}
+
+//Proxies are configured as `host:port`, a `socks5://` or `socks5h://`
+//prefix is accepted and dropped
+pub fn normalize_proxy(proxy: &str) -> Result<String, SocksError> {
+    let trimmed = proxy.trim();
+    let addr = trimmed
+        .strip_prefix("socks5h://")
+        .or_else(|| trimmed.strip_prefix("socks5://"))
+        .unwrap_or(trimmed)
+        .trim_end_matches('/');
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(addr.into()),
+        _ => Err(SocksError::InvalidProxy(proxy.into())),
+    }
+}
+
+//Opens a TCP stream to `host:port` through the proxy. The host name is
+//resolved by the proxy, so DNS doesn't leak and .onion hosts can be reached
+pub async fn connect(proxy: &str, host: &str, port: u16) -> Result<TcpStream, SocksError> {
+    if host.len() > 255 {
+        return Err(SocksError::HostTooLong(host.into()));
+    }
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    stream.write_all(&[SOCKS_VERSION, 1, NO_AUTH]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    match reply {
+        [SOCKS_VERSION, NO_AUTH] => (),
+        [SOCKS_VERSION, _] => return Err(SocksError::AuthRequired),
+        _ => return Err(SocksError::InvalidReply),
+    }
+
+    //IPv6 hosts come bracketed from URLs
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(SocksError::InvalidReply);
+    }
+    if reply[1] != 0 {
+        return Err(SocksError::ConnectFailed(reply_message(reply[1])));
+    }
+    //The bound address is not needed, but has to be read off the stream
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(SocksError::InvalidReply),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    const SUCCESS_REPLY: [u8; 10] = [SOCKS_VERSION, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+
+    //Local SOCKS5 server answering the greeting with `method` and the
+    //connect request with `reply`. Returns its address and the request
+    async fn stand_in(method: u8, reply: Vec<u8>) -> (String, JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [SOCKS_VERSION, 1, NO_AUTH]);
+            stream.write_all(&[SOCKS_VERSION, method]).await.unwrap();
+            if method != NO_AUTH {
+                return vec![];
+            }
+
+            let mut request = vec![0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            let addr_len = match request[3] {
+                ATYP_IPV4 => 4,
+                ATYP_IPV6 => 16,
+                _ => {
+                    let len = stream.read_u8().await.unwrap();
+                    request.push(len);
+                    len as usize
+                }
+            };
+            let mut addr = vec![0u8; addr_len + 2];
+            stream.read_exact(&mut addr).await.unwrap();
+            request.extend_from_slice(&addr);
+
+            stream.write_all(&reply).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            request
+        });
+        (addr, server)
+    }
+
+    #[tokio::test]
+    async fn connects_to_onion_host_by_name() {
+        let (proxy, server) = stand_in(NO_AUTH, SUCCESS_REPLY.to_vec()).await;
+        let host = "relayabcdefghijk.onion";
+        let mut stream = connect(&proxy, host, 443).await.unwrap();
+
+        let mut expected = vec![SOCKS_VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, host.len() as u8];
+        expected.extend_from_slice(host.as_bytes());
+        expected.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(server.await.unwrap(), expected);
+
+        //The stream is handed over right after the reply
+        let mut data = [0u8; 5];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn connects_to_ip_hosts() {
+        let (proxy, server) = stand_in(NO_AUTH, SUCCESS_REPLY.to_vec()).await;
+        connect(&proxy, "10.0.0.1", 80).await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            vec![SOCKS_VERSION, CMD_CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80]
+        );
+
+        let (proxy, server) = stand_in(NO_AUTH, SUCCESS_REPLY.to_vec()).await;
+        connect(&proxy, "[::1]", 80).await.unwrap();
+        let request = server.await.unwrap();
+        assert_eq!(request[3], ATYP_IPV6);
+        assert_eq!(request.len(), 4 + 16 + 2);
+    }
+
+    #[tokio::test]
+    async fn maps_failure_replies() {
+        let reply = vec![SOCKS_VERSION, 5, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+        let (proxy, _server) = stand_in(NO_AUTH, reply).await;
+        assert!(matches!(
+            connect(&proxy, "relay.example.com", 443).await,
+            Err(SocksError::ConnectFailed("connection refused"))
+        ));
+
+        let reply = vec![SOCKS_VERSION, 4, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+        let (proxy, _server) = stand_in(NO_AUTH, reply).await;
+        assert!(matches!(
+            connect(&proxy, "relay.example.com", 443).await,
+            Err(SocksError::ConnectFailed("host unreachable"))
+        ));
+
+        let (proxy, _server) = stand_in(0xff, vec![]).await;
+        assert!(matches!(
+            connect(&proxy, "relay.example.com", 443).await,
+            Err(SocksError::AuthRequired)
+        ));
+
+        let reply = vec![4, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+        let (proxy, _server) = stand_in(NO_AUTH, reply).await;
+        assert!(matches!(
+            connect(&proxy, "relay.example.com", 443).await,
+            Err(SocksError::InvalidReply)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_long_host_names() {
+        let host = "a".repeat(256);
+        assert!(matches!(
+            connect("127.0.0.1:9", &host, 443).await,
+            Err(SocksError::HostTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn normalizes_proxy_addresses() {
+        assert_eq!(normalize_proxy("127.0.0.1:9050").unwrap(), "127.0.0.1:9050");
+        assert_eq!(
+            normalize_proxy(" socks5h://localhost:9050/ ").unwrap(),
+            "localhost:9050"
+        );
+        assert_eq!(
+            normalize_proxy("socks5://[::1]:9050").unwrap(),
+            "[::1]:9050"
+        );
+        for invalid in ["localhost", ":9050", "localhost:port", "localhost:70000"] {
+            assert!(matches!(
+                normalize_proxy(invalid),
+                Err(SocksError::InvalidProxy(_))
+            ));
+        }
+    }
+}
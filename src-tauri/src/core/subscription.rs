@@ -1,11 +1,20 @@
 use std::collections::HashMap;
 
-use nostr::SubscriptionFilter;
+use log::warn;
+use secp256k1::schnorrsig::PublicKey;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::{
+    event::KIND_ENCRYPTED_DM, nip09::KIND_DELETION, nip11::RelayLimitation, nip17::KIND_GIFT_WRAP,
+};
+
+//Incremental subscriptions opened before merging them back in a new REQ
+const MAX_CHANNELS_PER_RELAY: usize = 8;
+
 pub struct Subscription {
-    filters: Vec<SubscriptionFilter>,
-    channels: HashMap<String, Channel>,
+    filters: Vec<Filter>,
+    channels: HashMap<String, Vec<Channel>>,
 }
 
 impl Subscription {
@@ -16,41 +25,563 @@ impl Subscription {
         }
     }
 
-    pub fn update_filters(&mut self, filters: Vec<SubscriptionFilter>) {
-        self.filters = filters;
+    pub fn update_filters(&mut self, filters: Vec<Filter>) {
+        self.filters = compact_filters(filters);
     }
 
-    pub fn get_filters(&self) -> Vec<SubscriptionFilter> {
+    pub fn get_filters(&self) -> Vec<Filter> {
         self.filters.clone()
     }
 
     pub fn _add_channel(&mut self, relay_url: String, channel: Channel) {
-        self.channels.insert(relay_url, channel);
+        self.channels.entry(relay_url).or_default().push(channel);
     }
 
-    pub fn remove_channel(&mut self, relay_url: &str) -> Option<Channel> {
-        self.channels.remove(relay_url)
+    pub fn remove_channels(&mut self, relay_url: &str) -> Vec<Channel> {
+        self.channels.remove(relay_url).unwrap_or_default()
     }
 
-    pub fn get_channel(&mut self, relay_url: &str) -> Channel {
-        self.channels
-            .entry(relay_url.into())
-            .or_insert(Channel::new(&relay_url))
-            .clone()
+    //Channels to close and to open so the relay's subscriptions ask for
+    //`filters`. Channels still wanted are kept, only what they don't cover is
+    //requested. What closed channels already fetched is asked from now on
+    pub fn update_channels(
+        &mut self,
+        relay_url: &str,
+        filters: Vec<Filter>,
+        limitation: Option<&RelayLimitation>,
+    ) -> SubscriptionDiff {
+        let filters = clamp_limits(filters, limitation);
+        let current = self.channels.remove(relay_url).unwrap_or_default();
+        let (kept, stale): (Vec<Channel>, Vec<Channel>) = current.into_iter().partition(|ch| {
+            ch.filters
+                .iter()
+                .all(|a| filters.iter().any(|d| a.is_within(d)))
+        });
+
+        let now = chrono::offset::Utc::now().timestamp();
+        let mut reqs: Vec<Filter> = vec![];
+        for a in stale.iter().flat_map(|ch| ch.filters.iter()) {
+            for d in filters.iter() {
+                if let Some(retained) = a.intersect(d) {
+                    reqs.push(retained.since(now));
+                }
+            }
+        }
+        let mut active: Vec<Filter> = kept.iter().flat_map(|ch| ch.filters.clone()).collect();
+        active.extend(reqs.iter().cloned());
+        for d in filters.iter() {
+            if let Some(remainder) = d.remainder(&active) {
+                reqs.push(remainder);
+            }
+        }
+
+        let max_id_length = limitation.and_then(|l| l.max_subid_length);
+        let new_channels = |reqs: Vec<Filter>| -> Vec<Channel> {
+            split_filters(compact_filters(reqs), limitation)
+                .into_iter()
+                .filter(|f| !f.is_empty())
+                .map(|f| Channel::new(relay_url, f, max_id_length))
+                .collect()
+        };
+        let mut open = new_channels(reqs);
+        let max_channels = limitation
+            .and_then(|l| l.max_subscriptions)
+            .unwrap_or(MAX_CHANNELS_PER_RELAY)
+            .min(MAX_CHANNELS_PER_RELAY);
+        let close = if kept.len() + open.len() > max_channels {
+            //Too fragmented, everything is asked again in as few REQs as possible
+            open = new_channels(filters);
+            self.channels.insert(relay_url.into(), open.clone());
+            kept.into_iter().chain(stale).collect()
+        } else {
+            let mut channels = kept;
+            channels.extend(open.iter().cloned());
+            self.channels.insert(relay_url.into(), channels);
+            stale
+        };
+        SubscriptionDiff { close, open }
     }
 }
 
+//Changes to send to a relay
+pub struct SubscriptionDiff {
+    pub close: Vec<Channel>,
+    pub open: Vec<Channel>,
+}
+
 #[derive(Clone)]
 pub struct Channel {
     pub relay_url: String,
     pub id: String,
+    pub filters: Vec<Filter>,
 }
 
 impl Channel {
-    pub fn new(relay_url: &str) -> Self {
+    pub fn new(relay_url: &str, filters: Vec<Filter>, max_id_length: Option<usize>) -> Self {
+        let mut id = Uuid::new_v4().to_string();
+        if let Some(max) = max_id_length {
+            id.truncate(max.max(1));
+        }
         Self {
-            id: Uuid::new_v4().to_string(),
+            id,
             relay_url: relay_url.into(),
+            filters,
+        }
+    }
+}
+
+//NIP-01 filter. nostr-rs filters take a single `#p` and only the kinds it
+//knows about, this one is built and sent as is
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Filter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<u64>,
+    #[serde(rename = "#e", default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+    #[serde(rename = "#p", default, skip_serializing_if = "Vec::is_empty")]
+    pub pubkeys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ids(mut self, ids: &[String]) -> Self {
+        extend_unique(&mut self.ids, ids.iter().cloned());
+        self
+    }
+
+    pub fn authors(mut self, authors: &[PublicKey]) -> Self {
+        extend_unique(&mut self.authors, authors.iter().map(|pk| pk.to_string()));
+        self
+    }
+
+    pub fn kinds(mut self, kinds: &[u64]) -> Self {
+        extend_unique(&mut self.kinds, kinds.iter().copied());
+        self
+    }
+
+    pub fn events(mut self, ids: &[String]) -> Self {
+        extend_unique(&mut self.events, ids.iter().cloned());
+        self
+    }
+
+    pub fn pubkeys(mut self, pubkeys: &[PublicKey]) -> Self {
+        extend_unique(&mut self.pubkeys, pubkeys.iter().map(|pk| pk.to_string()));
+        self
+    }
+
+    pub fn since(mut self, since: i64) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    pub fn until(mut self, until: i64) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    //Filters only asking for DMs and their deletions, the ones sent to DM
+    //relays
+    pub fn is_dm(&self) -> bool {
+        !self.kinds.is_empty()
+            && self
+                .kinds
+                .iter()
+                .all(|k| *k == KIND_ENCRYPTED_DM || *k == KIND_GIFT_WRAP || *k == KIND_DELETION)
+    }
+
+    //Two filters matching the union of their events, if they only differ in
+    //one of their lists. An empty list matches anything so it can't be merged
+    fn merge(&self, other: &Filter) -> Option<Filter> {
+        if self.limit.is_some()
+            || other.limit.is_some()
+            || self.since != other.since
+            || self.until != other.until
+        {
+            return None;
+        }
+        let diffs = [
+            self.ids != other.ids,
+            self.authors != other.authors,
+            self.kinds != other.kinds,
+            self.events != other.events,
+            self.pubkeys != other.pubkeys,
+        ];
+        let mut merged = self.clone();
+        match diffs {
+            [false, false, false, false, false] => Some(merged),
+            [true, false, false, false, false] if !self.ids.is_empty() && !other.ids.is_empty() => {
+                extend_unique(&mut merged.ids, other.ids.iter().cloned());
+                Some(merged)
+            }
+            [false, true, false, false, false]
+                if !self.authors.is_empty() && !other.authors.is_empty() =>
+            {
+                extend_unique(&mut merged.authors, other.authors.iter().cloned());
+                Some(merged)
+            }
+            [false, false, true, false, false]
+                if !self.kinds.is_empty() && !other.kinds.is_empty() =>
+            {
+                extend_unique(&mut merged.kinds, other.kinds.iter().copied());
+                Some(merged)
+            }
+            [false, false, false, true, false]
+                if !self.events.is_empty() && !other.events.is_empty() =>
+            {
+                extend_unique(&mut merged.events, other.events.iter().cloned());
+                Some(merged)
+            }
+            [false, false, false, false, true]
+                if !self.pubkeys.is_empty() && !other.pubkeys.is_empty() =>
+            {
+                extend_unique(&mut merged.pubkeys, other.pubkeys.iter().cloned());
+                Some(merged)
+            }
+            _ => None,
+        }
+    }
+
+    //True if the events matching the filter all match `other`
+    fn is_within(&self, other: &Filter) -> bool {
+        let (dims, other_dims) = (self.dims(), other.dims());
+        dims.iter()
+            .zip(other_dims.iter())
+            .all(|(d, o)| covers(o, d))
+            && self.until == other.until
+            && self.limit == other.limit
+            && other
+                .since
+                .map_or(true, |since| self.since.map_or(false, |s| s >= since))
+    }
+
+    //Filter matching the events both match
+    fn intersect(&self, other: &Filter) -> Option<Filter> {
+        if self.until != other.until || self.limit != other.limit {
+            return None;
+        }
+        let (dims, other_dims) = (self.dims(), other.dims());
+        let mut filter = self.clone();
+        for (i, (d, o)) in dims.into_iter().zip(other_dims).enumerate() {
+            let values = if d.is_empty() {
+                o
+            } else if o.is_empty() {
+                d
+            } else {
+                let values: Vec<String> = d.into_iter().filter(|v| o.contains(v)).collect();
+                if values.is_empty() {
+                    return None;
+                }
+                values
+            };
+            filter.set_dim(i, values);
+        }
+        filter.since = self.since.max(other.since);
+        Some(filter)
+    }
+
+    //Part of the filter the active ones don't ask for, None if it's covered.
+    //Only the values of a list another filter covers in full are taken out
+    fn remainder(&self, active: &[Filter]) -> Option<Filter> {
+        let dims = self.dims();
+        let mut remainder = self.clone();
+        for a in active
+            .iter()
+            .filter(|a| a.until == self.until && a.limit == self.limit)
+        {
+            let a_dims = a.dims();
+            let uncovered: Vec<usize> = (0..dims.len())
+                .filter(|i| !covers(&a_dims[*i], &dims[*i]))
+                .collect();
+            match uncovered.as_slice() {
+                [] => return None,
+                [i] if !a_dims[*i].is_empty() && !dims[*i].is_empty() => {
+                    let left: Vec<String> = remainder.dims()[*i]
+                        .iter()
+                        .filter(|v| !a_dims[*i].contains(v))
+                        .cloned()
+                        .collect();
+                    if left.is_empty() {
+                        return None;
+                    }
+                    remainder.set_dim(*i, left);
+                }
+                _ => (),
+            }
+        }
+        Some(remainder)
+    }
+
+    //The lists of the filter, kinds as strings
+    fn dims(&self) -> [Vec<String>; 5] {
+        [
+            self.ids.clone(),
+            self.authors.clone(),
+            self.kinds.iter().map(|k| k.to_string()).collect(),
+            self.events.clone(),
+            self.pubkeys.clone(),
+        ]
+    }
+
+    fn set_dim(&mut self, dim: usize, values: Vec<String>) {
+        match dim {
+            0 => self.ids = values,
+            1 => self.authors = values,
+            2 => self.kinds = values.iter().filter_map(|k| k.parse().ok()).collect(),
+            3 => self.events = values,
+            _ => self.pubkeys = values,
         }
     }
 }
+
+//An empty list matches anything
+fn covers(outer: &[String], inner: &[String]) -> bool {
+    outer.is_empty() || (!inner.is_empty() && inner.iter().all(|v| outer.contains(v)))
+}
+
+fn extend_unique<T: PartialEq>(values: &mut Vec<T>, new_values: impl Iterator<Item = T>) {
+    for value in new_values {
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+}
+
+//Merges filters until none can be, so the filter count doesn't grow with the
+//contacts. Merged filters take the place of the first of them
+pub fn compact_filters(filters: Vec<Filter>) -> Vec<Filter> {
+    let mut compacted: Vec<Filter> = vec![];
+    for mut filter in filters {
+        let mut at = compacted.len();
+        //A merged filter may now merge with another one
+        while let Some((i, merged)) = compacted
+            .iter()
+            .enumerate()
+            .find_map(|(i, f)| f.merge(&filter).map(|m| (i, m)))
+        {
+            compacted.remove(i);
+            at = at.min(i);
+            filter = merged;
+        }
+        compacted.insert(at, filter);
+    }
+    compacted
+}
+
+fn clamp_limits(filters: Vec<Filter>, limitation: Option<&RelayLimitation>) -> Vec<Filter> {
+    match limitation.and_then(|l| l.max_limit) {
+        Some(max_limit) => filters
+            .into_iter()
+            .map(|mut f| {
+                f.limit = f.limit.map(|l| l.min(max_limit));
+                f
+            })
+            .collect(),
+        None => filters,
+    }
+}
+
+//Groups the filters in the REQs a relay accepts, split in several
+//subscriptions when there are more than `max_filters`, up to `max_subscriptions`
+fn split_filters(filters: Vec<Filter>, limitation: Option<&RelayLimitation>) -> Vec<Vec<Filter>> {
+    let limitation = match limitation {
+        Some(limitation) => limitation,
+        None => return vec![filters],
+    };
+    let max_filters = match limitation.max_filters {
+        Some(max_filters) => max_filters.max(1),
+        None => return vec![filters],
+    };
+
+    let mut reqs: Vec<Vec<Filter>> = filters.chunks(max_filters).map(|c| c.to_vec()).collect();
+    if let Some(max_subscriptions) = limitation.max_subscriptions {
+        //Filters are in priority order, DMs first, the last ones are dropped
+        if reqs.len() > max_subscriptions.max(1) {
+            warn!(
+                "Relay accepts {} subscriptions, {} needed. Dropping the last filters",
+                max_subscriptions,
+                reqs.len()
+            );
+            reqs.truncate(max_subscriptions.max(1));
+        }
+    }
+    reqs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    const RELAY_URL: &str = "wss://relay.example.com";
+    const KIND_TEXT_NOTE: u64 = 1;
+
+    fn new_pks(count: usize) -> Vec<PublicKey> {
+        (0..count)
+            .map(|_| Keys::generate_from_os_random().unwrap().public_key)
+            .collect()
+    }
+
+    fn notes_of(pks: &[PublicKey]) -> Filter {
+        Filter::new().authors(pks).kinds(&[KIND_TEXT_NOTE])
+    }
+
+    fn channel_filters(diff: &SubscriptionDiff) -> Vec<Filter> {
+        diff.open.iter().flat_map(|ch| ch.filters.clone()).collect()
+    }
+
+    #[test]
+    fn adding_contact_only_requests_its_events() {
+        let pks = new_pks(2);
+        let mut subscription = Subscription::new();
+        let diff = subscription.update_channels(RELAY_URL, vec![notes_of(&pks[..1])], None);
+        assert!(diff.close.is_empty());
+        assert_eq!(channel_filters(&diff), vec![notes_of(&pks[..1])]);
+
+        let diff = subscription.update_channels(RELAY_URL, vec![notes_of(&pks)], None);
+        assert!(diff.close.is_empty());
+        assert_eq!(channel_filters(&diff), vec![notes_of(&pks[1..])]);
+        assert_eq!(channel_filters(&diff)[0].since, None);
+    }
+
+    #[test]
+    fn removing_contact_reopens_intersection_from_now() {
+        let pks = new_pks(2);
+        let mut subscription = Subscription::new();
+        let diff = subscription.update_channels(RELAY_URL, vec![notes_of(&pks)], None);
+        let stale_id = diff.open[0].id.clone();
+
+        let now = chrono::offset::Utc::now().timestamp();
+        let diff = subscription.update_channels(RELAY_URL, vec![notes_of(&pks[..1])], None);
+        assert_eq!(
+            diff.close
+                .iter()
+                .map(|ch| ch.id.clone())
+                .collect::<Vec<_>>(),
+            vec![stale_id]
+        );
+        let reopened = channel_filters(&diff);
+        assert_eq!(reopened.len(), 1);
+        assert!(reopened[0].since.unwrap() >= now);
+        assert_eq!(
+            Filter {
+                since: None,
+                ..reopened[0].clone()
+            },
+            notes_of(&pks[..1])
+        );
+    }
+
+    #[test]
+    fn unchanged_filters_keep_channels() {
+        let pks = new_pks(2);
+        let mut subscription = Subscription::new();
+        subscription.update_channels(RELAY_URL, vec![notes_of(&pks)], None);
+        let diff = subscription.update_channels(RELAY_URL, vec![notes_of(&pks)], None);
+        assert!(diff.close.is_empty());
+        assert!(diff.open.is_empty());
+    }
+
+    #[test]
+    fn too_many_channels_fall_back_to_full_req() {
+        let pks = new_pks(MAX_CHANNELS_PER_RELAY + 1);
+        let mut subscription = Subscription::new();
+        for count in 1..=MAX_CHANNELS_PER_RELAY {
+            let diff = subscription.update_channels(RELAY_URL, vec![notes_of(&pks[..count])], None);
+            assert!(diff.close.is_empty());
+            assert_eq!(diff.open.len(), 1);
+        }
+
+        let diff = subscription.update_channels(RELAY_URL, vec![notes_of(&pks)], None);
+        assert_eq!(diff.close.len(), MAX_CHANNELS_PER_RELAY);
+        assert_eq!(channel_filters(&diff), vec![notes_of(&pks)]);
+        assert_eq!(subscription.remove_channels(RELAY_URL).len(), 1);
+    }
+
+    #[test]
+    fn relay_subscription_limit_lowers_the_fallback() {
+        let pks = new_pks(3);
+        let limitation = RelayLimitation {
+            max_subscriptions: Some(2),
+            ..Default::default()
+        };
+        let mut subscription = Subscription::new();
+        for count in 1..=2 {
+            subscription.update_channels(
+                RELAY_URL,
+                vec![notes_of(&pks[..count])],
+                Some(&limitation),
+            );
+        }
+        let diff = subscription.update_channels(RELAY_URL, vec![notes_of(&pks)], Some(&limitation));
+        assert_eq!(diff.close.len(), 2);
+        assert_eq!(channel_filters(&diff), vec![notes_of(&pks)]);
+    }
+
+    #[test]
+    fn compacts_filters_differing_in_one_list() {
+        let pks = new_pks(3);
+        let compacted = compact_filters(vec![
+            notes_of(&pks[..1]),
+            notes_of(&pks[1..2]),
+            notes_of(&pks[2..]),
+        ]);
+        assert_eq!(compacted, vec![notes_of(&pks)]);
+
+        //Different authors and kinds can't be merged
+        let other = Filter::new()
+            .authors(&pks[1..2])
+            .kinds(&[KIND_ENCRYPTED_DM]);
+        let compacted = compact_filters(vec![notes_of(&pks[..1]), other.clone()]);
+        assert_eq!(compacted, vec![notes_of(&pks[..1]), other]);
+
+        //Neither can filters with a limit, nor an empty list
+        let limited = notes_of(&pks[1..2]).limit(10);
+        let compacted = compact_filters(vec![notes_of(&pks[..1]), limited.clone()]);
+        assert_eq!(compacted.len(), 2);
+        let any_author = Filter::new().kinds(&[KIND_TEXT_NOTE]);
+        let compacted = compact_filters(vec![notes_of(&pks[..1]), any_author]);
+        assert_eq!(compacted.len(), 2);
+    }
+
+    #[test]
+    fn filter_set_operations() {
+        let pks = new_pks(2);
+        let all = notes_of(&pks);
+        let first = notes_of(&pks[..1]);
+
+        assert!(first.is_within(&all));
+        assert!(!all.is_within(&first));
+        assert!(first.is_within(&Filter::new().kinds(&[KIND_TEXT_NOTE])));
+        assert!(!first.is_within(&all.clone().since(100)));
+        assert!(first.clone().since(200).is_within(&all.clone().since(100)));
+
+        assert_eq!(all.intersect(&first), Some(first.clone()));
+        let dms = Filter::new().authors(&pks).kinds(&[KIND_ENCRYPTED_DM]);
+        assert_eq!(all.intersect(&dms), None);
+
+        assert_eq!(
+            all.remainder(std::slice::from_ref(&first)),
+            Some(notes_of(&pks[1..]))
+        );
+        assert_eq!(first.remainder(std::slice::from_ref(&all)), None);
+        assert_eq!(all.remainder(&[dms]), Some(all.clone()));
+    }
+}
@@ -11,17 +11,24 @@ mod broker;
 mod cmd;
 mod core;
 
+use crate::core::nip13::PowCanceller;
 use broker::{start_broker, BrokerEvent};
 use log::info;
 
 use crate::cmd::{
-    add_contact, add_relay, generate_key_pair, get_config, get_conversation, remove_contact,
-    remove_relay, restore_key_pair, send_msg,
+    add_contact, add_contact_by_nip05, add_group_participant, add_relay, cancel_pow, create_group,
+    delete_message, edit_message, generate_key_pair, get_config, get_contact_list_publishing,
+    get_conversation, get_event_cache_metrics, get_group_conversation, get_pow_difficulty,
+    get_profile, get_proxy, get_relay_info, get_user_profile, list_groups, list_relays, react,
+    remove_contact, remove_group_participant, remove_relay, rename_group, restore_key_pair,
+    send_group_msg, send_msg, set_contact_encryption, set_contact_list_publishing,
+    set_pow_difficulty, set_proxy, set_user_profile, update_relay,
 };
 
 use tokio::sync::mpsc;
 pub struct AppState {
     pub core_command_sender: mpsc::Sender<BrokerEvent>,
+    pub pow_canceller: PowCanceller,
 }
 
 use tauri::Manager;
@@ -35,12 +42,14 @@ async fn main() {
         .setup(|app| {
             let main_window = app.get_window("main").expect("Failed to get main window");
             let (sender, receiver) = mpsc::channel::<BrokerEvent>(64);
+            let pow_canceller = PowCanceller::default();
 
-            tokio::spawn(start_broker(receiver, main_window));
+            tokio::spawn(start_broker(receiver, main_window, pow_canceller.clone()));
 
             let app_handle = app.handle();
             app_handle.manage(AppState {
                 core_command_sender: sender,
+                pow_canceller,
             });
             info!("Core broker spawned");
 
@@ -50,12 +59,38 @@ async fn main() {
             get_config,
             restore_key_pair,
             add_contact,
+            add_contact_by_nip05,
             add_relay,
             remove_relay,
+            update_relay,
+            list_relays,
+            get_relay_info,
+            get_event_cache_metrics,
+            get_proxy,
+            set_proxy,
+            get_pow_difficulty,
+            set_pow_difficulty,
+            cancel_pow,
             remove_contact,
+            set_contact_encryption,
+            get_contact_list_publishing,
+            set_contact_list_publishing,
             generate_key_pair,
             get_conversation,
-            send_msg
+            get_profile,
+            get_user_profile,
+            set_user_profile,
+            send_msg,
+            delete_message,
+            edit_message,
+            react,
+            create_group,
+            rename_group,
+            add_group_participant,
+            remove_group_participant,
+            get_group_conversation,
+            list_groups,
+            send_group_msg
         ])
         .run(tauri::generate_context!("tauri.conf.json"))
         .expect("error while running tauri application");